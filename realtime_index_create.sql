@@ -3,6 +3,22 @@ CREATE TABLE index_config (
                               name VARCHAR(64) NOT NULL UNIQUE,         -- 指数名称，例如 BTCUSDT
                               formula TEXT NOT NULL,                    -- 计算公式
                               is_active BOOLEAN DEFAULT TRUE,           -- 是否启用
+                              max_age_ms BIGINT NOT NULL DEFAULT 10000, -- 行情最大延迟(毫秒)，0 表示不检查
+                              min_sources INTEGER NOT NULL DEFAULT 1,   -- 发布指数所需的最少有效行情数
+                              edp_window_secs INTEGER NOT NULL DEFAULT 600, -- EDP 时间加权平均窗口(秒)，0 表示不计算
+                              breaker_max_tick_move NUMERIC(10,6) NOT NULL DEFAULT 0,   -- 熔断：单次计算最大相对变动，0 表示不检查
+                              breaker_max_minute_move NUMERIC(10,6) NOT NULL DEFAULT 0, -- 熔断：一分钟内最大相对变动，0 表示不检查
+                              breaker_confirm_ticks INTEGER NOT NULL DEFAULT 5,         -- 熔断后连续确认 N 次自动恢复，0 表示只能手动恢复
+                              outlier_margin NUMERIC(10,6) NOT NULL DEFAULT 0.003,      -- 偏离中位数超过该比例的行情被剔除，0 表示不剔除
+                              kline_intervals VARCHAR(64) NOT NULL DEFAULT '1m,5m,15m,1h,4h,1d', -- K 线周期，逗号分隔
+                              persist_interval_secs INTEGER NOT NULL DEFAULT 5,         -- 持久化 index_data 的间隔(秒)
+                              calc_interval_ms INTEGER NOT NULL DEFAULT 1000,           -- 输入行情无变化时的最长计算间隔(毫秒)
+                              min_publish_interval_ms INTEGER NOT NULL DEFAULT 100,     -- 行情变化触发计算时的最短发布间隔(毫秒)
+                              scale INTEGER NOT NULL DEFAULT 8,                         -- 指数保留的小数位数
+                              rounding_strategy VARCHAR(16) NOT NULL DEFAULT 'half_up', -- half_up / half_even / down / up / floor / ceiling
+                              derived_series VARCHAR(128) NOT NULL DEFAULT '',          -- 派生平滑序列，如 'EMA(30s),SMA(5m)'，发布为 BTCUSDT_EMA_30S 等
+                              quote_conversion VARCHAR(64) NOT NULL DEFAULT '',         -- 计价币换算：'mul:USDTUSD' / 'div:USDTUSD' / 'inverse'，空表示不换算
+                              activate_at TIMESTAMPTZ,                                  -- 计划启用时间：公式版本未生效的新指数在该时间后启用
                               created_at TIMESTAMPTZ DEFAULT now(),     -- 创建时间
                               updated_at TIMESTAMPTZ DEFAULT now()      -- 更新时间
 );
@@ -20,10 +36,21 @@ CREATE TRIGGER update_index_config_updated_at
     FOR EACH ROW
     EXECUTE FUNCTION update_updated_at_column();
 
+-- 指数公式历史版本，effective_from 之后生效，index_data_*.version_id 指向生成该值的版本
+CREATE TABLE index_config_version (
+                                      id BIGSERIAL PRIMARY KEY,
+                                      index_name VARCHAR(64) NOT NULL,        -- 指数名称
+                                      formula TEXT NOT NULL,                  -- 计算公式
+                                      effective_from TIMESTAMPTZ NOT NULL,    -- 生效时间
+                                      created_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE INDEX idx_index_config_version_name ON index_config_version (index_name, effective_from);
+
 CREATE TABLE task (
                       id BIGSERIAL PRIMARY KEY,
                       exchange_name VARCHAR(50) NOT NULL,
-                      symbol_ids TEXT NOT NULL,
+                      symbol_ids TEXT NOT NULL,                   -- 已弃用：订阅的交易对由启用的公式推导
                       is_enabled BOOLEAN DEFAULT TRUE,
                       created_at TIMESTAMP DEFAULT NOW(),
                       updated_at TIMESTAMP DEFAULT NOW()
@@ -33,7 +60,7 @@ CREATE TABLE symbol (
                         id BIGSERIAL PRIMARY KEY,
                         symbol_name VARCHAR(20) NOT NULL,
                         exchange_name VARCHAR(50) NOT NULL,
-                        third_symbol_name VARCHAR(50) NOT NULL,
+                        third_symbol_name VARCHAR(50) NOT NULL,     -- 交易所 instId，Bitget 合约写作 "USDT-FUTURES:BTCUSDT"
                         created_at TIMESTAMP DEFAULT NOW(),
                         updated_at TIMESTAMP DEFAULT NOW()
 );
@@ -43,6 +70,14 @@ CREATE TABLE index_data_btcusdt (
                                   symbol VARCHAR(45) NOT NULL,         -- 指数名称
                                   last NUMERIC(36,18) NOT NULL,       -- 最新指数值
                                   formula VARCHAR(512) NOT NULL,       -- 计算公式
+                                  degraded BOOLEAN NOT NULL DEFAULT FALSE, -- 是否降级
+                                  edp NUMERIC(36,18),                  -- 时间加权平均价
+                                  breaker_tripped BOOLEAN NOT NULL DEFAULT FALSE, -- 是否熔断中
+                                  version_id BIGINT,                   -- 公式版本
+                                  raw_last NUMERIC(36,18),             -- 换算前的指数值
+                                  conversion_rate NUMERIC(36,18),      -- 换算使用的参考指数值
+                                  excluded_sources TEXT NOT NULL DEFAULT '', -- 被剔除的行情
+                                  stale_sources TEXT NOT NULL DEFAULT '', -- 过期的行情
                                   created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
                                   updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
 );
@@ -51,6 +86,14 @@ COMMENT ON COLUMN index_data_btcusdt.id IS '自增主键';
 COMMENT ON COLUMN index_data_btcusdt.symbol IS '指数名称';
 COMMENT ON COLUMN index_data_btcusdt.last IS '最新指数值';
 COMMENT ON COLUMN index_data_btcusdt.formula IS '计算公式';
+COMMENT ON COLUMN index_data_btcusdt.degraded IS '是否降级（有效行情数不足）';
+COMMENT ON COLUMN index_data_btcusdt.edp IS '时间加权平均价（EDP）';
+COMMENT ON COLUMN index_data_btcusdt.breaker_tripped IS '是否熔断中（保持熔断前的值）';
+COMMENT ON COLUMN index_data_btcusdt.version_id IS '公式版本（index_config_version.id）';
+COMMENT ON COLUMN index_data_btcusdt.raw_last IS '计价币换算前的指数值';
+COMMENT ON COLUMN index_data_btcusdt.conversion_rate IS '计价币换算使用的参考指数值';
+COMMENT ON COLUMN index_data_btcusdt.excluded_sources IS '因偏离过大被剔除的行情，逗号分隔';
+COMMENT ON COLUMN index_data_btcusdt.stale_sources IS '因超过 max_age_ms 未更新被剔除的行情，逗号分隔';
 
 
 CREATE TABLE index_data_ethusdt (
@@ -58,6 +101,14 @@ CREATE TABLE index_data_ethusdt (
                                   symbol VARCHAR(45) NOT NULL,         -- 指数名称
                                   last NUMERIC(36,18) NOT NULL,       -- 最新指数值
                                   formula VARCHAR(512) NOT NULL,       -- 计算公式
+                                  degraded BOOLEAN NOT NULL DEFAULT FALSE, -- 是否降级
+                                  edp NUMERIC(36,18),                  -- 时间加权平均价
+                                  breaker_tripped BOOLEAN NOT NULL DEFAULT FALSE, -- 是否熔断中
+                                  version_id BIGINT,                   -- 公式版本
+                                  raw_last NUMERIC(36,18),             -- 换算前的指数值
+                                  conversion_rate NUMERIC(36,18),      -- 换算使用的参考指数值
+                                  excluded_sources TEXT NOT NULL DEFAULT '', -- 被剔除的行情
+                                  stale_sources TEXT NOT NULL DEFAULT '', -- 过期的行情
                                   created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
                                   updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
 );
@@ -66,6 +117,142 @@ COMMENT ON COLUMN index_data_ethusdt.id IS '自增主键';
 COMMENT ON COLUMN index_data_ethusdt.symbol IS '指数名称';
 COMMENT ON COLUMN index_data_ethusdt.last IS '最新指数值';
 COMMENT ON COLUMN index_data_ethusdt.formula IS '计算公式';
+COMMENT ON COLUMN index_data_ethusdt.degraded IS '是否降级（有效行情数不足）';
+COMMENT ON COLUMN index_data_ethusdt.edp IS '时间加权平均价（EDP）';
+COMMENT ON COLUMN index_data_ethusdt.breaker_tripped IS '是否熔断中（保持熔断前的值）';
+COMMENT ON COLUMN index_data_ethusdt.version_id IS '公式版本（index_config_version.id）';
+COMMENT ON COLUMN index_data_ethusdt.raw_last IS '计价币换算前的指数值';
+COMMENT ON COLUMN index_data_ethusdt.conversion_rate IS '计价币换算使用的参考指数值';
+COMMENT ON COLUMN index_data_ethusdt.excluded_sources IS '因偏离过大被剔除的行情，逗号分隔';
+COMMENT ON COLUMN index_data_ethusdt.stale_sources IS '因超过 max_age_ms 未更新被剔除的行情，逗号分隔';
+
+CREATE TABLE circuit_breaker_event (
+                                       id BIGSERIAL PRIMARY KEY,
+                                       index_name VARCHAR(64) NOT NULL,   -- 指数名称
+                                       event VARCHAR(16) NOT NULL,        -- tripped / resumed / reset
+                                       reason VARCHAR(16) NOT NULL,       -- tick_move / minute_move / confirmed / manual
+                                       reference_price NUMERIC(36,18),    -- 熔断前最后一次发布的值
+                                       candidate_price NUMERIC(36,18),    -- 候选值
+                                       move_ratio NUMERIC(36,18),         -- 相对变动比例
+                                       created_at TIMESTAMPTZ DEFAULT now()
+);
+
+-- 手动恢复熔断：插入一行 (index_name)，计算任务处理后写入 handled_at
+CREATE TABLE circuit_breaker_reset (
+                                       id BIGSERIAL PRIMARY KEY,
+                                       index_name VARCHAR(64) NOT NULL,   -- 指数名称
+                                       requested_at TIMESTAMPTZ DEFAULT now(),
+                                       handled_at TIMESTAMPTZ
+);
+
+CREATE TABLE settlement_config (
+                                   id SERIAL PRIMARY KEY,
+                                   index_name VARCHAR(64) NOT NULL,          -- 指数名称
+                                   frequency VARCHAR(10) NOT NULL,           -- daily / weekly
+                                   weekday SMALLINT,                         -- weekly 时的星期几，1=周一 ... 7=周日
+                                   settle_time TIME NOT NULL,                -- 结算时间(UTC)
+                                   window_minutes INTEGER NOT NULL DEFAULT 30, -- 取结算时间前 N 分钟的平均值
+                                   is_active BOOLEAN DEFAULT TRUE,
+                                   created_at TIMESTAMPTZ DEFAULT now(),
+                                   updated_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE TABLE settlement_price (
+                                  id BIGSERIAL PRIMARY KEY,
+                                  index_name VARCHAR(64) NOT NULL,         -- 指数名称
+                                  frequency VARCHAR(10) NOT NULL,          -- daily / weekly
+                                  settle_at TIMESTAMPTZ NOT NULL,          -- 结算时间
+                                  price NUMERIC(36,18) NOT NULL,           -- 结算价
+                                  window_minutes INTEGER NOT NULL,         -- 平均窗口(分钟)
+                                  samples_count INTEGER NOT NULL,          -- 窗口内采样数
+                                  sources TEXT NOT NULL,                   -- 窗口内参与计算的行情
+                                  created_at TIMESTAMPTZ DEFAULT now(),
+                                  UNIQUE (index_name, frequency, settle_at)
+);
+
+-- 标记价格：mark = index * (1 + clamp(EMA(premium)))，premium = (perp - index) / index
+-- 数据写入 mark_price_data_{name}，由服务启动时创建
+CREATE TABLE mark_price_config (
+                                   id SERIAL PRIMARY KEY,
+                                   name VARCHAR(64) NOT NULL UNIQUE,                 -- 标记价格名称，不能与指数名称重复
+                                   index_name VARCHAR(64) NOT NULL,                  -- 现货指数名称
+                                   perp_formula TEXT NOT NULL,                       -- 永续合约价格公式
+                                   ema_window_secs INTEGER NOT NULL DEFAULT 60,      -- 溢价 EMA 窗口(秒)
+                                   premium_window_secs INTEGER NOT NULL DEFAULT 60,  -- 溢价平均窗口(秒)
+                                   max_premium_rate NUMERIC(10,6) NOT NULL DEFAULT 0.005, -- EMA 溢价上下限，0 表示不限制
+                                   max_age_ms BIGINT NOT NULL DEFAULT 10000,         -- 永续行情最大延迟(毫秒)，0 表示不检查
+                                   funding_interval_secs INTEGER NOT NULL DEFAULT 28800, -- 资金费周期(秒)，从 UTC 0 点对齐，0 表示不计算
+                                   interest_rate NUMERIC(10,6) NOT NULL DEFAULT 0.0001,  -- 每个资金费周期的利率
+                                   funding_interest_clamp NUMERIC(10,6) NOT NULL DEFAULT 0.0005, -- 利率与溢价之差的上下限
+                                   max_funding_rate NUMERIC(10,6) NOT NULL DEFAULT 0.0075, -- 资金费率上下限，0 表示不限制
+                                   is_active BOOLEAN DEFAULT TRUE,
+                                   created_at TIMESTAMPTZ DEFAULT now(),
+                                   updated_at TIMESTAMPTZ DEFAULT now()
+);
+
+-- 资金费率：funding_rate = clamp(premium_avg + clamp(interest_rate - premium_avg, ±interest_clamp), ±max_funding_rate)
+CREATE TABLE funding_rate (
+                              id BIGSERIAL PRIMARY KEY,
+                              name VARCHAR(64) NOT NULL,                -- 标记价格名称（mark_price_config.name）
+                              index_name VARCHAR(64) NOT NULL,          -- 现货指数名称
+                              funding_at TIMESTAMPTZ NOT NULL,          -- 资金费结算时间
+                              interval_secs INTEGER NOT NULL,           -- 资金费周期(秒)
+                              premium_avg NUMERIC(36,18) NOT NULL,      -- 周期内溢价指数时间加权平均
+                              samples_count INTEGER NOT NULL,           -- 周期内溢价采样数
+                              interest_rate NUMERIC(36,18) NOT NULL,    -- 利率
+                              interest_clamp NUMERIC(36,18) NOT NULL,   -- 利率与溢价之差的上下限
+                              interest_component NUMERIC(36,18) NOT NULL, -- clamp(interest_rate - premium_avg)
+                              raw_rate NUMERIC(36,18) NOT NULL,         -- 限制前的资金费率
+                              max_funding_rate NUMERIC(36,18) NOT NULL, -- 资金费率上下限
+                              funding_rate NUMERIC(36,18) NOT NULL,     -- 资金费率
+                              created_at TIMESTAMPTZ DEFAULT now(),
+                              UNIQUE (name, funding_at)
+);
+
+-- 一篮子指数：level = Σ(quantity × 成分指数) / divisor，数据写入 index_data_{name}，与普通指数一样生成 K 线
+CREATE TABLE basket_config (
+                               id SERIAL PRIMARY KEY,
+                               name VARCHAR(64) NOT NULL UNIQUE,                 -- 篮子名称，不能与指数名称重复
+                               base_level NUMERIC(36,18) NOT NULL DEFAULT 1000,  -- 首次建仓时的点位
+                               divisor NUMERIC(36,18),                           -- 当前除数，NULL 表示尚未建仓
+                               kline_intervals VARCHAR(64) NOT NULL DEFAULT '1m,5m,15m,1h,4h,1d', -- K 线周期，逗号分隔
+                               persist_interval_secs INTEGER NOT NULL DEFAULT 5, -- 持久化间隔(秒)
+                               scale INTEGER NOT NULL DEFAULT 8,                 -- 保留的小数位数
+                               is_active BOOLEAN DEFAULT TRUE,
+                               created_at TIMESTAMPTZ DEFAULT now(),
+                               updated_at TIMESTAMPTZ DEFAULT now()
+);
+
+-- 篮子成分：weight 或 quantity 为调仓目标（两者都有时以 quantity 为准），修改后插入调仓请求生效
+CREATE TABLE basket_constituent (
+                                    id SERIAL PRIMARY KEY,
+                                    basket_name VARCHAR(64) NOT NULL,         -- 篮子名称
+                                    index_name VARCHAR(64) NOT NULL,          -- 成分指数名称
+                                    weight NUMERIC(36,18),                    -- 目标权重
+                                    quantity NUMERIC(36,18),                  -- 目标数量
+                                    current_quantity NUMERIC(36,18),          -- 最近一次调仓后的实际数量
+                                    created_at TIMESTAMPTZ DEFAULT now(),
+                                    updated_at TIMESTAMPTZ DEFAULT now(),
+                                    UNIQUE (basket_name, index_name)
+);
+
+-- 调仓请求：插入一行 (basket_name)，计算任务按当前成分和价格调仓后写入 handled_at
+CREATE TABLE basket_rebalance_request (
+                                          id BIGSERIAL PRIMARY KEY,
+                                          basket_name VARCHAR(64) NOT NULL,     -- 篮子名称
+                                          requested_at TIMESTAMPTZ DEFAULT now(),
+                                          handled_at TIMESTAMPTZ
+);
+
+CREATE TABLE basket_rebalance (
+                                  id BIGSERIAL PRIMARY KEY,
+                                  basket_name VARCHAR(64) NOT NULL,         -- 篮子名称
+                                  old_divisor NUMERIC(36,18),               -- 调仓前除数，首次建仓为 NULL
+                                  new_divisor NUMERIC(36,18) NOT NULL,      -- 调仓后除数
+                                  level NUMERIC(36,18) NOT NULL,            -- 调仓时点位
+                                  holdings TEXT NOT NULL,                   -- 调仓后持仓，如 BTCUSDT:0.01,ETHUSDT:0.2
+                                  created_at TIMESTAMPTZ DEFAULT now()
+);
 
 create table index_kline_data
 (
@@ -1,13 +1,29 @@
-INSERT INTO index_config (name, formula) VALUES
-                                             ('BTCUSDT', '(Binance.BTCUSDT + Bitget.BTCUSDT)/2'),
-                                             ('ETHUSDT', '(Binance.ETHUSDT + Bitget.ETHUSDT)/2');
+INSERT INTO index_config (name, formula, derived_series) VALUES
+                                                             ('BTCUSDT', 'avg(Binance.BTCUSDT, Bitget.BTCUSDT)', 'EMA(30s),SMA(5m)'),
+                                                             ('ETHUSDT', 'avg(Binance.ETHUSDT, Bitget.ETHUSDT)', '');
+
+INSERT INTO index_config_version (index_name, formula, effective_from)
+SELECT name, formula, now() FROM index_config;
 
 
 INSERT INTO symbol (symbol_name, exchange_name, third_symbol_name) VALUES
                                                                        ('BTCUSDT', 'Binance', 'BTCUSDT'),
                                                                        ('ETHUSDT', 'Binance', 'ETHUSDT'),
                                                                        ('BTCUSDT', 'Bitget', 'BTCUSDT'),
-                                                                       ('ETHUSDT', 'Bitget', 'ETHUSDT');
+                                                                       ('ETHUSDT', 'Bitget', 'ETHUSDT'),
+                                                                       ('BTCUSDT_PERP', 'Bitget', 'USDT-FUTURES:BTCUSDT'),
+                                                                       ('BTCUSDT_PERP', 'Okex', 'BTC-USDT-SWAP');
 INSERT INTO task (exchange_name, symbol_ids, is_enabled) VALUES
                                                              ('Binance', '1,2', TRUE),
-                                                             ('Bitget', '3,4', TRUE);
\ No newline at end of file
+                                                             ('Bitget', '3,4', TRUE),
+                                                             ('Okex', '', TRUE);
+INSERT INTO settlement_config (index_name, frequency, weekday, settle_time, window_minutes) VALUES
+                                                                                               ('BTCUSDT', 'daily', NULL, '08:00:00', 30),
+                                                                                               ('BTCUSDT', 'weekly', 5, '08:00:00', 30);
+INSERT INTO mark_price_config (name, index_name, perp_formula, ema_window_secs, premium_window_secs, max_premium_rate) VALUES
+                                                                                                                         ('BTCUSDT_MARK', 'BTCUSDT', 'avg(Bitget.BTCUSDT_PERP, Okex.BTCUSDT_PERP)', 60, 60, 0.005);
+INSERT INTO basket_config (name, base_level) VALUES
+                                                 ('MAJORS', 1000);
+INSERT INTO basket_constituent (basket_name, index_name, weight) VALUES
+                                                                     ('MAJORS', 'BTCUSDT', 0.6),
+                                                                     ('MAJORS', 'ETHUSDT', 0.4);
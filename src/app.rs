@@ -1,24 +1,36 @@
+use crate::core::index::basket_calculator::{parse_holdings, BasketCalculator, BasketTarget};
 use crate::core::index::index_calculator::IndexCalculator;
-use crate::core::model::{IndexKlineData, KlineInterval, Symbol};
+use crate::core::index::mark_price_calculator::MarkPriceCalculator;
+use crate::core::model::{BasketConfig, IndexConfigVersion, IndexKlineData, MarkPriceConfig, PriceUpdate, SettlementConfig, Symbol};
 use crate::exchanges::ExchangeEnum;
-use crate::tasks::{index_calculator_task, market_printer, price_updater};
+use crate::tasks::{funding_scheduler, index_calculator_task, market_printer, price_updater, settlement_scheduler};
 
-use rust_decimal::Decimal;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use crate::core::db::config_repository::ConfigRepository;
 use crate::core::exchange::exchange_factory::ExchangeFactory;
+use crate::core::exchange::exchange_initializer::ExchangeInitializer;
 use crate::core::exchange::exchange_manager::ExchangeManager;
-use crate::core::index::calculator_manager::CalculatorManager;
+use crate::core::index::calculator_manager::{conversion_deps, resolve_order, resolve_routes, CalculatorManager};
+use crate::core::index::formula;
 use crate::core::trade::trade_repository::TradeRepository;
-use tokio::sync::mpsc::UnboundedSender;
-use tracing::info;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::{info, warn};
 
 pub struct App {
     pub manager: Arc<ExchangeManager>,
     pub calculators: Arc<CalculatorManager>,
     pub task_symbols_map: Arc<HashMap<ExchangeEnum, Vec<Symbol>>>,
+    pub settlement_configs: Vec<SettlementConfig>,
+    pub mark_configs: Vec<MarkPriceConfig>,
+    pub basket_configs: Vec<BasketConfig>,
+    /// WebSocket 客户端推送的行情
+    pub price_rx: UnboundedReceiver<PriceUpdate>,
+    /// 尚未生效的公式版本
+    pub pending_versions: Vec<IndexConfigVersion>,
+    /// 启动时已加载的最大公式版本 id
+    pub last_version_id: i64,
 }
 
 impl App {
@@ -27,30 +39,84 @@ impl App {
 
         // 获取配置
         let index_configs = config_repo.get_active_configs().await?;
+        let settlement_configs = config_repo.get_active_settlement_configs().await?;
+        let mark_configs = config_repo.get_active_mark_price_configs().await?;
+        let basket_configs = config_repo.get_active_basket_configs().await?;
         let tasks = config_repo.get_enabled_tasks().await?;
         info!("Loaded {} tasks from DB", tasks.len());
 
-        // 查询 task 对应的 symbol
-        let mut task_symbols_map: HashMap<ExchangeEnum, Vec<Symbol>> = HashMap::new();
-        for task in &tasks {
-            let ids: Vec<i32> = task
-                .symbol_ids
-                .split(',')
-                .filter_map(|s| s.parse().ok())
-                .collect();
-            let symbols = config_repo
-                .get_symbols_by_ids(&ids, &task.exchange_name)
-                .await?;
-            task_symbols_map.insert(
-                ExchangeEnum::from_name(&task.exchange_name).unwrap(),
-                symbols,
-            );
+        // 尚未生效的公式版本，其引用的行情也需要订阅
+        let versions = config_repo.get_config_versions_after(0).await?;
+        let last_version_id = versions.iter().map(|v| v.id).max().unwrap_or(0);
+        let now = chrono::Utc::now();
+        let pending_versions: Vec<IndexConfigVersion> = versions
+            .into_iter()
+            .filter(|v| v.effective_from > now && index_configs.iter().any(|c| c.name == v.index_name))
+            .collect();
+        for v in &pending_versions {
+            info!("Index {} formula version {} pending until {}: {}", v.index_name, v.id, v.effective_from, v.formula);
+        }
+        let formulas: Vec<(&str, &str)> = index_configs
+            .iter()
+            .map(|c| (c.name.as_str(), c.formula.as_str()))
+            .collect();
+        let pending_formulas: Vec<(&str, &str)> = pending_versions
+            .iter()
+            .map(|v| (v.index_name.as_str(), v.formula.as_str()))
+            .collect();
+        // 标记价格与指数共用行情路由，名称不能重复
+        if let Some(mark) = mark_configs.iter().find(|m| index_configs.iter().any(|c| c.name == m.name)) {
+            anyhow::bail!("mark price {} conflicts with index of the same name", mark.name);
+        }
+        // 篮子与指数共用 K 线和 index_data 表名
+        if let Some(basket) = basket_configs
+            .iter()
+            .find(|b| index_configs.iter().any(|c| c.name == b.name) || mark_configs.iter().any(|m| m.name == b.name))
+        {
+            anyhow::bail!("basket {} conflicts with index or mark price of the same name", basket.name);
+        }
+        let mark_configs: Vec<MarkPriceConfig> = mark_configs
+            .into_iter()
+            .filter(|m| {
+                let active = index_configs.iter().any(|c| c.name == m.index_name);
+                if !active {
+                    warn!("mark price {} references inactive index {}", m.name, m.index_name);
+                }
+                active
+            })
+            .collect();
+        let mark_formulas: Vec<(&str, &str)> = mark_configs
+            .iter()
+            .map(|m| (m.name.as_str(), m.perp_formula.as_str()))
+            .collect();
+
+        // 根据启用公式中的行情引用推导各交易所需要订阅的 symbol，task 只决定交易所是否启用
+        let enabled_exchanges: HashSet<String> = tasks
+            .iter()
+            .filter(|t| t.is_enabled)
+            .map(|t| t.exchange_name.clone())
+            .collect();
+        let all_formulas: Vec<(&str, &str)> = formulas
+            .iter()
+            .chain(&pending_formulas)
+            .chain(&mark_formulas)
+            .copied()
+            .collect();
+        let mut subscribed: HashSet<String> = HashSet::new();
+        let task_symbols_map =
+            ExchangeInitializer::resolve_symbols(&config_repo, &all_formulas, &enabled_exchanges, &mut subscribed).await?;
+        for (exchange, symbols) in &task_symbols_map {
+            let names: Vec<&str> = symbols.iter().map(|s| s.symbol_name.as_str()).collect();
+            info!("{} subscribes {:?}", exchange.name(), names);
         }
         let task_symbols_map = Arc::new(task_symbols_map);
 
         // TradeRepository
         let trade_repo = Arc::new(TradeRepository::new(20));
 
+        // WebSocket 客户端通过该 channel 推送行情
+        let (price_tx, price_rx) = tokio::sync::mpsc::unbounded_channel();
+
         // ExchangeManager
         let manager = Arc::new(ExchangeManager::new());
         for task in &tasks {
@@ -64,36 +130,121 @@ impl App {
                     .map(|s| (s.third_symbol_name.clone(), s.symbol_name.clone()))
                     .collect();
                 let client =
-                    ExchangeFactory::create(exchange_enum.clone(), trade_repo.clone(), symbol_map);
+                    ExchangeFactory::create(exchange_enum.clone(), trade_repo.clone(), symbol_map, price_tx.clone());
                 let symbol_names: HashSet<String> = symbols
                     .iter()
                     .map(|s| s.third_symbol_name.clone())
                     .collect();
+                let book_symbols: HashSet<String> = symbols
+                    .iter()
+                    .filter(|s| s.book)
+                    .map(|s| s.third_symbol_name.clone())
+                    .collect();
                 manager
-                    .add_exchange(exchange_enum, client, symbol_names)
+                    .add_exchange(exchange_enum, client, symbol_names, book_symbols)
                     .await;
             }
         }
 
         manager.clone().spawn_reconnect(10_000);
 
-        // 初始化计算器
+        // 初始化计算器，index(NAME) 存在循环依赖时拒绝启动
+        for config in &index_configs {
+            if let Some(conversion) = config.quote_conversion() {
+                if let Some(index) = conversion.index_ref().filter(|i| !index_configs.iter().any(|c| &c.name == i)) {
+                    warn!("name {} quote conversion references inactive index {}", config.name, index);
+                }
+                info!("Index {} quote conversion {}", config.name, conversion);
+            }
+        }
+        let order = resolve_order(&formulas, &conversion_deps(&index_configs))?;
+        let routes = resolve_routes(&all_formulas);
         let mut calculators_map: HashMap<String, IndexCalculator> = HashMap::new();
         for config in &index_configs {
-            calculators_map.insert(
-                config.name.clone(),
-                IndexCalculator::new(
-                    config.name.clone(),
-                    Decimal::from_f64_retain(0.003).unwrap(),
+            calculators_map.insert(config.name.clone(), IndexCalculator::from_config(config, &settlement_configs));
+        }
+        let mut marks_map: HashMap<String, MarkPriceCalculator> = HashMap::new();
+        for mark in &mark_configs {
+            let Some(index_config) = index_configs.iter().find(|c| c.name == mark.index_name) else {
+                continue;
+            };
+            // 永续价格使用与现货指数相同的异常剔除比例
+            let perp = IndexCalculator::new(mark.name.clone(), index_config.outlier_margin, mark.max_age_ms, 0);
+            let mut calculator = MarkPriceCalculator::new(
+                mark.name.clone(),
+                mark.index_name.clone(),
+                mark.perp_formula.clone(),
+                perp,
+                mark.ema_window_secs as i64 * 1000,
+                mark.premium_window_secs as i64 * 1000,
+                mark.max_premium_rate,
+            );
+            // 资金费率取整个资金费周期的溢价平均
+            calculator.retention_ms = mark.funding_interval_secs.max(mark.premium_window_secs) as i64 * 1000;
+            marks_map.insert(mark.name.clone(), calculator);
+        }
+        // 一篮子指数，已建仓的从上次调仓结果恢复，否则在成分都有发布值后按 base_level 建仓
+        let mut baskets_map: HashMap<String, BasketCalculator> = HashMap::new();
+        for basket in &basket_configs {
+            let constituents = config_repo.get_basket_constituents(&basket.name).await?;
+            for c in &constituents {
+                if !index_configs.iter().any(|i| i.name == c.index_name) {
+                    warn!("basket {} references inactive index {}", basket.name, c.index_name);
+                }
+            }
+            let targets: Vec<BasketTarget> = constituents
+                .iter()
+                .map(|c| BasketTarget {
+                    index_name: c.index_name.clone(),
+                    weight: c.weight,
+                    quantity: c.quantity,
+                })
+                .collect();
+            // 以最近一次调仓记录为准恢复除数和持仓，与 basket_config.divisor 不一致时拒绝启动，避免点位跳变
+            let last = config_repo.get_last_basket_rebalance(&basket.name).await?;
+            let (divisor, holdings) = match (basket.divisor, last) {
+                (None, None) => (None, Vec::new()),
+                (Some(divisor), Some((last_divisor, holdings))) if divisor == last_divisor => {
+                    (Some(divisor), parse_holdings(&holdings)?)
+                }
+                (divisor, last) => anyhow::bail!(
+                    "basket {} divisor {:?} does not match last rebalance {:?}",
+                    basket.name, divisor, last
                 ),
+            };
+            for h in &holdings {
+                if !index_configs.iter().any(|i| i.name == h.index_name) {
+                    anyhow::bail!("basket {} holds inactive index {}", basket.name, h.index_name);
+                }
+                if !targets.iter().any(|t| t.index_name == h.index_name) {
+                    warn!(
+                        "basket {} still holds {} removed from constituents until the next rebalance",
+                        basket.name, h.index_name
+                    );
+                }
+            }
+            info!("Basket {} divisor {:?}, constituents {}", basket.name, divisor, constituents.len());
+            baskets_map.insert(
+                basket.name.clone(),
+                BasketCalculator::new(basket.name.clone(), basket.base_level, divisor, holdings, targets),
             );
         }
-        let calculators = Arc::new(CalculatorManager::new(calculators_map));
+        let calculators = Arc::new(
+            CalculatorManager::new(calculators_map, order, routes)
+                .with_marks(marks_map)
+                .with_baskets(baskets_map),
+        );
 
         Ok(Self {
             manager,
             calculators,
             task_symbols_map,
+            settlement_configs,
+            mark_configs,
+            basket_configs,
+            price_rx,
+            pending_versions,
+            last_version_id,
         })
     }
 
@@ -107,11 +258,22 @@ impl App {
         let calculators = self.calculators.clone();
         let task_symbols_map = self.task_symbols_map.clone();
 
-        // 启动三个异步任务
+        // 启动异步任务：行情推送 -> 计算器 -> 指数计算
+        let (changed_tx, changed_rx) = tokio::sync::mpsc::unbounded_channel();
         tokio::spawn(price_updater::run_price_updater(
-            manager.clone(),
+            self.price_rx,
+            calculators.clone(),
+            changed_tx,
+        ));
+        tokio::spawn(settlement_scheduler::run_settlement_scheduler(
             calculators.clone(),
-            task_symbols_map.clone(),
+            self.settlement_configs.clone(),
+            config_repo.clone(),
+        ));
+        tokio::spawn(funding_scheduler::run_funding_scheduler(
+            calculators.clone(),
+            self.mark_configs.clone(),
+            config_repo.clone(),
         ));
         let config_repo_arc = config_repo.clone();
         tokio::spawn(index_calculator_task::run_index_calculator(
@@ -119,14 +281,11 @@ impl App {
             index_configs.clone(),
             config_repo_arc,
             kline_tx,
-            vec![
-                KlineInterval::OneMinute,
-                KlineInterval::FiveMinutes,
-                KlineInterval::FifteenMinutes,
-                KlineInterval::OneHour,
-                KlineInterval::FourHours,
-                KlineInterval::OneDay,
-            ],
+            changed_rx,
+            self.pending_versions,
+            self.last_version_id,
+            self.basket_configs,
+            manager.clone(),
         ));
 
         // tokio::spawn(market_printer::run_market_printer(
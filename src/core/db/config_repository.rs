@@ -1,35 +1,261 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, Utc};
+use std::fmt::{Display, Formatter};
+use sqlx::postgres::PgListener;
 use sqlx::{PgPool, Result};
-use crate::core::model::{IndexConfig, IndexData, IndexKlineData, Symbol, Task};
+use rust_decimal::Decimal;
+use crate::core::index::basket_calculator::{format_holdings, BasketRebalance};
+use crate::core::index::calculator_manager::{conversion_deps, resolve_order};
+use crate::core::index::formula::{self, FormulaError};
+use crate::core::model::{BasketConfig, BasketConstituent, CircuitBreakerEvent, FundingRate, IndexConfig, IndexConfigVersion, IndexData, IndexKlineData, MarkPriceConfig, MarkPriceData, SettlementConfig, SettlementPrice, Symbol, Task};
+
+/// schedule_config 新增公式版本时的通知频道
+const CONFIG_VERSION_CHANNEL: &str = "index_config_version";
 
 pub struct ConfigRepository {
     pool: PgPool,
 }
 
+/// 公式中无法解析的引用
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnknownReference {
+    /// symbol 表中不存在该交易所的交易对，例如 Binance.BTCUSTD
+    Symbol(String),
+    /// 交易对存在，但该交易所没有启用的 task
+    ExchangeNotEnabled(String),
+    /// index(NAME) 引用的指数不存在或未启用
+    Index(String),
+}
+
+impl Display for UnknownReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnknownReference::Symbol(r) => write!(f, "{}: symbol not found", r),
+            UnknownReference::ExchangeNotEnabled(r) => write!(f, "{}: exchange has no enabled task", r),
+            UnknownReference::Index(name) => write!(f, "index({}): index not found", name),
+        }
+    }
+}
+
+/// 指数配置校验错误
+#[derive(Debug)]
+pub enum ConfigError {
+    InvalidFormula(FormulaError),
+    /// 聚合函数之外相加的含行情项数大于 1，不做偏离剔除
+    UnaggregatedSources(usize),
+    UnknownReferences(Vec<UnknownReference>),
+    CircularDependency(String),
+    Database(sqlx::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::InvalidFormula(e) => write!(f, "invalid formula: {}", e),
+            ConfigError::UnaggregatedSources(n) => write!(
+                f,
+                "formula sums {} market sources outside an aggregate, use avg(...) or median(...) so missing sources and outliers are excluded",
+                n
+            ),
+            ConfigError::UnknownReferences(refs) => {
+                let refs: Vec<String> = refs.iter().map(|r| r.to_string()).collect();
+                write!(f, "unknown references: {}", refs.join("; "))
+            }
+            ConfigError::CircularDependency(msg) => write!(f, "{}", msg),
+            ConfigError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<sqlx::Error> for ConfigError {
+    fn from(e: sqlx::Error) -> Self {
+        ConfigError::Database(e)
+    }
+}
+
 impl ConfigRepository {
     pub fn new(pool: PgPool) -> Self {
         Self { pool }
     }
 
-    /// 获取所有启用的指数配置
+    /// 获取所有启用的指数配置，formula 和 version_id 取当前生效的公式版本
     pub async fn get_active_configs(&self) -> Result<Vec<IndexConfig>> {
-        let configs = sqlx::query_as::<_, IndexConfig>(
+        let mut configs = sqlx::query_as::<_, IndexConfig>(
             "SELECT * FROM index_config WHERE is_active = TRUE ORDER BY id",
         )
             .fetch_all(&self.pool)
             .await?;
+
+        let versions: HashMap<String, IndexConfigVersion> = self
+            .get_current_versions()
+            .await?
+            .into_iter()
+            .map(|v| (v.index_name.clone(), v))
+            .collect();
+        for config in &mut configs {
+            if let Some(version) = versions.get(&config.name) {
+                config.formula = version.formula.clone();
+                config.version_id = Some(version.id);
+            }
+        }
         Ok(configs)
     }
 
-    /// 新增一个配置
-    pub async fn insert_config(&self, name: &str, formula: &str) -> Result<()> {
+    /// 新增或更新一个配置，立即生效，写入前校验公式
+    pub async fn insert_config(&self, name: &str, formula: &str) -> std::result::Result<(), ConfigError> {
+        self.schedule_config(name, formula, Utc::now()).await?;
+        Ok(())
+    }
+
+    /// 新增一个公式版本，effective_from 之前仍使用旧公式，返回版本 id，并通知计算服务立即加载。
+    /// 已生效的版本同时写入 index_config；新指数记录 activate_at，到时由计算服务启用并开始计算
+    pub async fn schedule_config(
+        &self,
+        name: &str,
+        formula: &str,
+        effective_from: DateTime<Utc>,
+    ) -> std::result::Result<i64, ConfigError> {
+        self.validate_formula(name, formula).await?;
+        let effective_now = effective_from <= Utc::now();
+
+        let mut tx = self.pool.begin().await?;
         sqlx::query(
-            "INSERT INTO index_config (name, formula) VALUES ($1, $2)
-             ON CONFLICT (name) DO UPDATE SET formula = EXCLUDED.formula, updated_at = now()",
+            "INSERT INTO index_config (name, formula, is_active, activate_at) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (name) DO NOTHING",
         )
             .bind(name)
             .bind(formula)
-            .execute(&self.pool)
+            .bind(effective_now)
+            .bind((!effective_now).then_some(effective_from))
+            .execute(&mut *tx)
+            .await?;
+        let version_id = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO index_config_version (index_name, formula, effective_from) VALUES ($1, $2, $3) RETURNING id",
+        )
+            .bind(name)
+            .bind(formula)
+            .bind(effective_from)
+            .fetch_one(&mut *tx)
+            .await?;
+        if effective_now {
+            sqlx::query("UPDATE index_config SET formula = $2, updated_at = now() WHERE name = $1")
+                .bind(name)
+                .bind(formula)
+                .execute(&mut *tx)
+                .await?;
+        }
+        // 事务提交后才会送达
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CONFIG_VERSION_CHANNEL)
+            .bind(version_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(version_id)
+    }
+
+    /// 尚未启用、等待 activate_at 的新指数
+    pub async fn get_scheduled_configs(&self) -> Result<Vec<IndexConfig>> {
+        let configs = sqlx::query_as::<_, IndexConfig>(
+            "SELECT * FROM index_config WHERE is_active = FALSE AND activate_at IS NOT NULL ORDER BY id",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(configs)
+    }
+
+    /// 启用 activate_at 已到的新指数，返回启用的指数名称
+    pub async fn activate_scheduled_configs(&self) -> Result<Vec<String>> {
+        let names = sqlx::query_scalar::<_, String>(
+            "UPDATE index_config SET is_active = TRUE, activate_at = NULL, updated_at = now()
+             WHERE activate_at <= now() RETURNING name",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(names)
+    }
+
+    /// 监听新公式版本的通知，payload 为版本 id
+    pub async fn listen_config_versions(&self) -> Result<PgListener> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CONFIG_VERSION_CHANNEL).await?;
+        Ok(listener)
+    }
+
+    /// 每个指数当前生效的公式版本
+    pub async fn get_current_versions(&self) -> Result<Vec<IndexConfigVersion>> {
+        let versions = sqlx::query_as::<_, IndexConfigVersion>(
+            "SELECT DISTINCT ON (index_name) * FROM index_config_version
+             WHERE effective_from <= now()
+             ORDER BY index_name, effective_from DESC, id DESC",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(versions)
+    }
+
+    /// id 大于 after_id 的公式版本，按 id 排序
+    pub async fn get_config_versions_after(&self, after_id: i64) -> Result<Vec<IndexConfigVersion>> {
+        let versions = sqlx::query_as::<_, IndexConfigVersion>(
+            "SELECT * FROM index_config_version WHERE id > $1 ORDER BY id",
+        )
+            .bind(after_id)
+            .fetch_all(&self.pool)
             .await?;
+        Ok(versions)
+    }
+
+    /// 校验公式：语法正确；多个行情必须放在聚合函数中；每个 Exchange.SYMBOL 在 symbol 表中存在且该交易所有启用的 task；
+    /// 每个 index(NAME) 指向启用的指数，且不会形成循环依赖
+    pub async fn validate_formula(&self, name: &str, formula: &str) -> std::result::Result<(), ConfigError> {
+        let expr = formula::parse(formula).map_err(ConfigError::InvalidFormula)?;
+        let terms = expr.summed_source_terms();
+        if terms > 1 {
+            return Err(ConfigError::UnaggregatedSources(terms));
+        }
+
+        let enabled_exchanges: HashSet<String> = self
+            .get_enabled_tasks()
+            .await?
+            .into_iter()
+            .map(|t| t.exchange_name)
+            .collect();
+
+        let mut unknown = Vec::new();
+        let mut checked = HashSet::new();
+        for source in expr.sources() {
+            let key = source.key();
+            if !checked.insert(key.clone()) {
+                continue;
+            }
+            if self.get_symbol(&source.symbol, &source.exchange).await?.is_none() {
+                unknown.push(UnknownReference::Symbol(key));
+            } else if !enabled_exchanges.contains(&source.exchange) {
+                unknown.push(UnknownReference::ExchangeNotEnabled(key));
+            }
+        }
+
+        let configs = self.get_active_configs().await?;
+        for index_name in expr.index_refs() {
+            if index_name != name && !configs.iter().any(|c| c.name == index_name) {
+                unknown.push(UnknownReference::Index(index_name.to_string()));
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(ConfigError::UnknownReferences(unknown));
+        }
+
+        // 用新公式替换后检查循环依赖
+        let mut formulas: Vec<(&str, &str)> = configs
+            .iter()
+            .filter(|c| c.name != name)
+            .map(|c| (c.name.as_str(), c.formula.as_str()))
+            .collect();
+        formulas.push((name, formula));
+        resolve_order(&formulas, &conversion_deps(&configs)).map_err(|e| ConfigError::CircularDependency(e.to_string()))?;
+
         Ok(())
     }
 
@@ -93,6 +319,18 @@ impl ConfigRepository {
         Ok(symbols)
     }
 
+    /// 根据交易对名称和交易所获取 symbol
+    pub async fn get_symbol(&self, symbol_name: &str, exchange_name: &str) -> Result<Option<Symbol>> {
+        let symbol = sqlx::query_as::<_, Symbol>(
+            "SELECT * FROM symbol WHERE symbol_name = $1 AND exchange_name = $2 LIMIT 1"
+        )
+            .bind(symbol_name)
+            .bind(exchange_name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(symbol)
+    }
+
     /// 新增或更新 symbol
     pub async fn upsert_symbol(&self, symbol_name: &str, exchange_name: &str, third_symbol_name: &str) -> Result<()> {
         sqlx::query(
@@ -131,11 +369,19 @@ impl ConfigRepository{
         // 注意：表名直接拼接，需要保证安全性，防止 SQL 注入
         let sql = format!(
             r#"
-            INSERT INTO {} (id, symbol, last, formula, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6)
+            INSERT INTO {} (id, symbol, last, formula, degraded, edp, breaker_tripped, version_id, raw_last, conversion_rate, excluded_sources, stale_sources, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
             ON CONFLICT (id) DO UPDATE
               SET last = EXCLUDED.last,
                   formula = EXCLUDED.formula,
+                  degraded = EXCLUDED.degraded,
+                  edp = EXCLUDED.edp,
+                  breaker_tripped = EXCLUDED.breaker_tripped,
+                  version_id = EXCLUDED.version_id,
+                  raw_last = EXCLUDED.raw_last,
+                  conversion_rate = EXCLUDED.conversion_rate,
+                  excluded_sources = EXCLUDED.excluded_sources,
+                  stale_sources = EXCLUDED.stale_sources,
                   updated_at = EXCLUDED.updated_at
             "#,
             table_name
@@ -144,8 +390,16 @@ impl ConfigRepository{
         sqlx::query(&sql)
             .bind(index_data.id)
             .bind(&index_data.symbol)
-            .bind(&index_data.last)
+            .bind(index_data.last)
             .bind(&index_data.formula)
+            .bind(index_data.degraded)
+            .bind(index_data.edp)
+            .bind(index_data.breaker_tripped)
+            .bind(index_data.version_id)
+            .bind(index_data.raw_last)
+            .bind(index_data.conversion_rate)
+            .bind(&index_data.excluded_sources)
+            .bind(&index_data.stale_sources)
             .bind(index_data.created_at)
             .bind(index_data.updated_at)
             .execute(&self.pool)
@@ -168,14 +422,14 @@ impl ConfigRepository {
           updated_at = EXCLUDED.updated_at
 "#;
 
-        sqlx::query(&sql)
+        sqlx::query(sql)
             .bind(index_data.id)
             .bind(&index_data.symbol)
-            .bind(&index_data.interval.to_string())  // 绑定新字段
-            .bind(&index_data.open)
-            .bind(&index_data.high)
-            .bind(&index_data.low)
-            .bind(&index_data.close)
+            .bind(index_data.interval.to_string())  // 绑定新字段
+            .bind(index_data.open)
+            .bind(index_data.high)
+            .bind(index_data.low)
+            .bind(index_data.close)
             .bind(index_data.ts)
             .bind(index_data.created_at)
             .bind(index_data.updated_at)
@@ -187,6 +441,351 @@ impl ConfigRepository {
 }
 
 impl ConfigRepository {
+    /// 写入熔断事件
+    pub async fn insert_breaker_event(&self, event: &CircuitBreakerEvent) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO circuit_breaker_event (index_name, event, reason, reference_price, candidate_price, move_ratio, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+            .bind(&event.index_name)
+            .bind(&event.event)
+            .bind(&event.reason)
+            .bind(event.reference_price)
+            .bind(event.candidate_price)
+            .bind(event.move_ratio)
+            .bind(event.created_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// 取出未处理的手动恢复请求并标记为已处理，返回需要恢复的指数名称
+    pub async fn take_breaker_resets(&self) -> Result<Vec<String>> {
+        let names = sqlx::query_scalar::<_, String>(
+            "UPDATE circuit_breaker_reset SET handled_at = now() WHERE handled_at IS NULL RETURNING index_name",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(names)
+    }
+
+    /// 获取所有启用的结算配置
+    pub async fn get_active_settlement_configs(&self) -> Result<Vec<SettlementConfig>> {
+        let configs = sqlx::query_as::<_, SettlementConfig>(
+            "SELECT * FROM settlement_config WHERE is_active = TRUE ORDER BY id",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(configs)
+    }
+
+    /// 写入结算价，同一指数同一频率同一结算时间只保留一条
+    pub async fn insert_settlement_price(&self, settlement: &SettlementPrice) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO settlement_price (index_name, frequency, settle_at, price, window_minutes, samples_count, sources, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (index_name, frequency, settle_at) DO UPDATE
+              SET price = EXCLUDED.price,
+                  window_minutes = EXCLUDED.window_minutes,
+                  samples_count = EXCLUDED.samples_count,
+                  sources = EXCLUDED.sources
+            "#,
+        )
+            .bind(&settlement.index_name)
+            .bind(settlement.frequency)
+            .bind(settlement.settle_at)
+            .bind(settlement.price)
+            .bind(settlement.window_minutes)
+            .bind(settlement.samples_count)
+            .bind(&settlement.sources)
+            .bind(settlement.created_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+impl ConfigRepository {
+    /// 获取所有启用的一篮子指数配置
+    pub async fn get_active_basket_configs(&self) -> Result<Vec<BasketConfig>> {
+        let configs = sqlx::query_as::<_, BasketConfig>(
+            "SELECT * FROM basket_config WHERE is_active = TRUE ORDER BY id",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(configs)
+    }
+
+    /// 获取某个篮子当前的成分
+    pub async fn get_basket_constituents(&self, basket_name: &str) -> Result<Vec<BasketConstituent>> {
+        let constituents = sqlx::query_as::<_, BasketConstituent>(
+            "SELECT * FROM basket_constituent WHERE basket_name = $1 ORDER BY id",
+        )
+            .bind(basket_name)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(constituents)
+    }
+
+    /// 未处理的调仓请求 (请求 id, 篮子名称)，调仓结果保存成功后才标记为已处理
+    pub async fn get_pending_basket_rebalances(&self) -> Result<Vec<(i64, String)>> {
+        let requests = sqlx::query_as::<_, (i64, String)>(
+            "SELECT id, basket_name FROM basket_rebalance_request WHERE handled_at IS NULL ORDER BY id",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(requests)
+    }
+
+    /// 最近一次调仓后的除数和持仓，从未建仓时返回 None
+    pub async fn get_last_basket_rebalance(&self, basket_name: &str) -> Result<Option<(Decimal, String)>> {
+        let last = sqlx::query_as::<_, (Decimal, String)>(
+            "SELECT new_divisor, holdings FROM basket_rebalance WHERE basket_name = $1 ORDER BY id DESC LIMIT 1",
+        )
+            .bind(basket_name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(last)
+    }
+
+    /// 保存调仓结果：更新除数和各成分的实际数量，写入调仓记录，并在同一事务中将 request_ids 标记为已处理
+    pub async fn save_basket_rebalance(&self, rebalance: &BasketRebalance, request_ids: &[i64]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("UPDATE basket_config SET divisor = $1, updated_at = now() WHERE name = $2")
+            .bind(rebalance.new_divisor)
+            .bind(&rebalance.basket_name)
+            .execute(&mut *tx)
+            .await?;
+
+        // 已移出篮子的成分不再持有
+        sqlx::query(
+            "UPDATE basket_constituent SET current_quantity = NULL, updated_at = now() WHERE basket_name = $1",
+        )
+            .bind(&rebalance.basket_name)
+            .execute(&mut *tx)
+            .await?;
+        for holding in &rebalance.holdings {
+            sqlx::query(
+                "UPDATE basket_constituent SET current_quantity = $1, updated_at = now() WHERE basket_name = $2 AND index_name = $3",
+            )
+                .bind(holding.quantity)
+                .bind(&rebalance.basket_name)
+                .bind(&holding.index_name)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO basket_rebalance (basket_name, old_divisor, new_divisor, level, holdings)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+            .bind(&rebalance.basket_name)
+            .bind(rebalance.old_divisor)
+            .bind(rebalance.new_divisor)
+            .bind(rebalance.level)
+            .bind(format_holdings(&rebalance.holdings))
+            .execute(&mut *tx)
+            .await?;
+
+        if !request_ids.is_empty() {
+            sqlx::query("UPDATE basket_rebalance_request SET handled_at = now() WHERE id = ANY($1)")
+                .bind(request_ids)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// 获取所有启用的标记价格配置
+    pub async fn get_active_mark_price_configs(&self) -> Result<Vec<MarkPriceConfig>> {
+        let configs = sqlx::query_as::<_, MarkPriceConfig>(
+            "SELECT * FROM mark_price_config WHERE is_active = TRUE ORDER BY id",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(configs)
+    }
+
+    pub async fn insert_mark_price(
+        &self,
+        table_name: &str, // 动态表名
+        data: &MarkPriceData,
+    ) -> Result<()> {
+        let sql = format!(
+            r#"
+            INSERT INTO {} (id, name, index_name, index_price, perp_price, premium_index, premium_avg, premium_ema, mark_price, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (id) DO UPDATE
+              SET index_price = EXCLUDED.index_price,
+                  perp_price = EXCLUDED.perp_price,
+                  premium_index = EXCLUDED.premium_index,
+                  premium_avg = EXCLUDED.premium_avg,
+                  premium_ema = EXCLUDED.premium_ema,
+                  mark_price = EXCLUDED.mark_price,
+                  updated_at = EXCLUDED.updated_at
+            "#,
+            table_name
+        );
+
+        sqlx::query(&sql)
+            .bind(data.id)
+            .bind(&data.name)
+            .bind(&data.index_name)
+            .bind(data.index_price)
+            .bind(data.perp_price)
+            .bind(data.premium_index)
+            .bind(data.premium_avg)
+            .bind(data.premium_ema)
+            .bind(data.mark_price)
+            .bind(data.created_at)
+            .bind(data.updated_at)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// 写入资金费率，同一标记价格同一结算时间只保留一条
+    pub async fn insert_funding_rate(&self, rate: &FundingRate) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO funding_rate (name, index_name, funding_at, interval_secs, premium_avg, samples_count,
+                                      interest_rate, interest_clamp, interest_component, raw_rate, max_funding_rate,
+                                      funding_rate, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            ON CONFLICT (name, funding_at) DO UPDATE
+              SET interval_secs = EXCLUDED.interval_secs,
+                  premium_avg = EXCLUDED.premium_avg,
+                  samples_count = EXCLUDED.samples_count,
+                  interest_rate = EXCLUDED.interest_rate,
+                  interest_clamp = EXCLUDED.interest_clamp,
+                  interest_component = EXCLUDED.interest_component,
+                  raw_rate = EXCLUDED.raw_rate,
+                  max_funding_rate = EXCLUDED.max_funding_rate,
+                  funding_rate = EXCLUDED.funding_rate
+            "#,
+        )
+            .bind(&rate.name)
+            .bind(&rate.index_name)
+            .bind(rate.funding_at)
+            .bind(rate.interval_secs)
+            .bind(rate.premium_avg)
+            .bind(rate.samples_count)
+            .bind(rate.interest_rate)
+            .bind(rate.interest_clamp)
+            .bind(rate.interest_component)
+            .bind(rate.raw_rate)
+            .bind(rate.max_funding_rate)
+            .bind(rate.funding_rate)
+            .bind(rate.created_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// 查询某个标记价格 [from, to) 内的资金费率，按结算时间升序
+    pub async fn get_funding_rates(&self, name: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<FundingRate>> {
+        let rates = sqlx::query_as::<_, FundingRate>(
+            "SELECT * FROM funding_rate WHERE name = $1 AND funding_at >= $2 AND funding_at < $3 ORDER BY funding_at",
+        )
+            .bind(name)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rates)
+    }
+
+    /// 某个标记价格最近一次的资金费率
+    pub async fn get_latest_funding_rate(&self, name: &str) -> Result<Option<FundingRate>> {
+        let rate = sqlx::query_as::<_, FundingRate>(
+            "SELECT * FROM funding_rate WHERE name = $1 ORDER BY funding_at DESC LIMIT 1",
+        )
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(rate)
+    }
+
+    pub async fn create_mark_price_table_if_not_exists(&self, name: &str) -> anyhow::Result<()> {
+        let table_name = format!("mark_price_data_{}", name.to_lowercase());
+
+        let sql_create = format!(r#"
+            CREATE TABLE IF NOT EXISTS {} (
+                id BIGSERIAL PRIMARY KEY,
+                name VARCHAR(64) NOT NULL,
+                index_name VARCHAR(64) NOT NULL,
+                index_price NUMERIC(36, 18) NOT NULL,
+                perp_price NUMERIC(36, 18),
+                premium_index NUMERIC(36, 18),
+                premium_avg NUMERIC(36, 18) NOT NULL,
+                premium_ema NUMERIC(36, 18) NOT NULL,
+                mark_price NUMERIC(36, 18) NOT NULL,
+                created_at TIMESTAMPTZ DEFAULT now(),
+                updated_at TIMESTAMPTZ DEFAULT now()
+            )
+        "#, table_name);
+        sqlx::query(&sql_create).execute(&self.pool).await?;
+
+        let comments = vec![
+            ("id", "自增主键"),
+            ("name", "标记价格名称"),
+            ("index_name", "现货指数名称"),
+            ("index_price", "现货指数"),
+            ("perp_price", "永续合约价格"),
+            ("premium_index", "溢价指数 (perp - index) / index"),
+            ("premium_avg", "溢价指数时间加权平均"),
+            ("premium_ema", "溢价指数 EMA"),
+            ("mark_price", "标记价格"),
+        ];
+        for (col, comment) in comments {
+            let sql_comment = format!("COMMENT ON COLUMN {}.{} IS '{}'", table_name, col, comment);
+            sqlx::query(&sql_comment).execute(&self.pool).await?;
+        }
+
+        let sql_owner = format!("ALTER TABLE {} OWNER TO postgres", table_name);
+        sqlx::query(&sql_owner).execute(&self.pool).await?;
+
+        Ok(())
+    }
+}
+
+impl ConfigRepository {
+    /// 已存在的 index_config 补充新增列，默认值与 realtime_index_create.sql 一致，需在加载配置前执行
+    pub async fn migrate_index_config(&self) -> anyhow::Result<()> {
+        let new_columns = vec![
+            ("max_age_ms", "BIGINT NOT NULL DEFAULT 10000"),
+            ("min_sources", "INTEGER NOT NULL DEFAULT 1"),
+            ("edp_window_secs", "INTEGER NOT NULL DEFAULT 600"),
+            ("breaker_max_tick_move", "NUMERIC(10,6) NOT NULL DEFAULT 0"),
+            ("breaker_max_minute_move", "NUMERIC(10,6) NOT NULL DEFAULT 0"),
+            ("breaker_confirm_ticks", "INTEGER NOT NULL DEFAULT 5"),
+            ("outlier_margin", "NUMERIC(10,6) NOT NULL DEFAULT 0.003"),
+            ("kline_intervals", "VARCHAR(64) NOT NULL DEFAULT '1m,5m,15m,1h,4h,1d'"),
+            ("persist_interval_secs", "INTEGER NOT NULL DEFAULT 5"),
+            ("calc_interval_ms", "INTEGER NOT NULL DEFAULT 1000"),
+            ("min_publish_interval_ms", "INTEGER NOT NULL DEFAULT 100"),
+            ("scale", "INTEGER NOT NULL DEFAULT 8"),
+            ("rounding_strategy", "VARCHAR(16) NOT NULL DEFAULT 'half_up'"),
+            ("derived_series", "VARCHAR(128) NOT NULL DEFAULT ''"),
+            ("quote_conversion", "VARCHAR(64) NOT NULL DEFAULT ''"),
+            ("activate_at", "TIMESTAMPTZ"),
+        ];
+        for (col, definition) in new_columns {
+            let sql_alter = format!("ALTER TABLE index_config ADD COLUMN IF NOT EXISTS {} {}", col, definition);
+            sqlx::query(&sql_alter).execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
     pub async fn create_index_data_table_if_not_exists(&self, config_name: &str) -> anyhow::Result<()> {
         let table_name = format!("index_data_{}", config_name.to_lowercase());
 
@@ -197,18 +796,53 @@ impl ConfigRepository {
                 symbol VARCHAR(45) NOT NULL,
                 last NUMERIC(36, 18) NOT NULL,
                 formula VARCHAR(512) NOT NULL,
+                degraded BOOLEAN NOT NULL DEFAULT FALSE,
+                edp NUMERIC(36, 18),
+                breaker_tripped BOOLEAN NOT NULL DEFAULT FALSE,
+                version_id BIGINT,
+                raw_last NUMERIC(36, 18),
+                conversion_rate NUMERIC(36, 18),
+                excluded_sources TEXT NOT NULL DEFAULT '',
+                stale_sources TEXT NOT NULL DEFAULT '',
                 created_at TIMESTAMPTZ DEFAULT now(),
                 updated_at TIMESTAMPTZ DEFAULT now()
             )
         "#, table_name);
         sqlx::query(&sql_create).execute(&self.pool).await?;
 
+        // 已存在的表补充新增列
+        let new_columns = vec![
+            ("degraded", "BOOLEAN NOT NULL DEFAULT FALSE"),
+            ("edp", "NUMERIC(36, 18)"),
+            ("breaker_tripped", "BOOLEAN NOT NULL DEFAULT FALSE"),
+            ("version_id", "BIGINT"),
+            ("raw_last", "NUMERIC(36, 18)"),
+            ("conversion_rate", "NUMERIC(36, 18)"),
+            ("excluded_sources", "TEXT NOT NULL DEFAULT ''"),
+            ("stale_sources", "TEXT NOT NULL DEFAULT ''"),
+        ];
+        for (col, definition) in new_columns {
+            let sql_alter = format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
+                table_name, col, definition
+            );
+            sqlx::query(&sql_alter).execute(&self.pool).await?;
+        }
+
         // 2️⃣ 添加列注释
         let comments = vec![
             ("id", "自增主键"),
             ("symbol", "指数名称"),
             ("last", "最新指数值"),
             ("formula", "计算公式"),
+            ("degraded", "是否降级（有效行情数不足）"),
+            ("edp", "时间加权平均价（EDP）"),
+            ("breaker_tripped", "是否熔断中（保持熔断前的值）"),
+            ("version_id", "公式版本（index_config_version.id）"),
+            ("raw_last", "计价币换算前的指数值"),
+            ("conversion_rate", "计价币换算使用的参考指数值"),
+            ("excluded_sources", "因偏离过大被剔除的行情，逗号分隔"),
+            ("stale_sources", "因超过 max_age_ms 未更新被剔除的行情，逗号分隔"),
         ];
         for (col, comment) in comments {
             let sql_comment = format!("COMMENT ON COLUMN {}.{} IS '{}'", table_name, col, comment);
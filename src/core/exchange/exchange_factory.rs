@@ -1,9 +1,10 @@
-use crate::core::model::Exchange;
+use crate::core::model::{Exchange, PriceUpdate};
 use crate::core::trade::trade_repository::TradeRepository;
 use crate::core::ws::websocket_listener::WebSocketStatusListener;
 use crate::exchanges::{binance, bitget, okex, ExchangeEnum};
 use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
 
 pub struct ExchangeFactory;
 
@@ -12,6 +13,7 @@ impl ExchangeFactory {
         exch: ExchangeEnum,
         trade_repo: Arc<TradeRepository>,
         symbol_map: HashMap<String, String>,
+        price_sender: UnboundedSender<PriceUpdate>,
     ) -> Arc<dyn WebSocketStatusListener> {
         match exch {
             ExchangeEnum::Bitget => Arc::new(bitget::client::BitgetWebSocketClient::new(
@@ -22,6 +24,7 @@ impl ExchangeFactory {
                 symbol_map,
                 "ping".to_string(),
                 15_000,
+                price_sender,
             )),
             ExchangeEnum::Binance => Arc::new(binance::client::BinanceWebSocketClient::new(
                 Exchange {
@@ -31,6 +34,7 @@ impl ExchangeFactory {
                 symbol_map,
                 "".to_string(),
                 15_000,
+                price_sender,
             )),
             ExchangeEnum::Okex => Arc::new(okex::client::OkexWebSocketClient::new(
                 Exchange {
@@ -40,6 +44,7 @@ impl ExchangeFactory {
                 symbol_map,
                 "ping".to_string(),
                 15_000,
+                price_sender,
             )),
         }
     }
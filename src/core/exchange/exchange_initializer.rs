@@ -1,10 +1,14 @@
-use crate::core::model::Symbol;
+use crate::core::model::{PriceUpdate, Symbol};
 use crate::core::trade::trade_repository::TradeRepository;
 use crate::exchanges::ExchangeEnum;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
 use crate::core::exchange::exchange_factory::ExchangeFactory;
 use crate::core::exchange::exchange_manager::ExchangeManager;
+use crate::core::db::config_repository::ConfigRepository;
+use crate::core::index::formula::{self, PriceField};
+use tracing::warn;
 
 pub struct ExchangeInitializer;
 
@@ -13,16 +17,67 @@ impl ExchangeInitializer {
         tasks_symbols_map: &HashMap<ExchangeEnum, Vec<Symbol>>,
         manager: Arc<ExchangeManager>,
         trade_repo: Arc<TradeRepository>,
+        price_sender: UnboundedSender<PriceUpdate>,
     ) {
         for (exch, symbols) in tasks_symbols_map {
             let symbol_map: HashMap<String, String> = symbols
                 .iter()
                 .map(|s| (s.third_symbol_name.clone(), s.symbol_name.clone()))
                 .collect();
-            let client = ExchangeFactory::create(exch.clone(), trade_repo.clone(), symbol_map);
+            let client = ExchangeFactory::create(exch.clone(), trade_repo.clone(), symbol_map, price_sender.clone());
             let symbol_names: HashSet<String> =
                 symbols.iter().map(|s| s.third_symbol_name.clone()).collect();
-            manager.add_exchange(exch.clone(), client, symbol_names).await;
+            let book_symbols: HashSet<String> =
+                symbols.iter().filter(|s| s.book).map(|s| s.third_symbol_name.clone()).collect();
+            manager.add_exchange(exch.clone(), client, symbol_names, book_symbols).await;
         }
     }
+
+    /// 根据公式中的行情引用推导各交易所需要订阅的 symbol，formulas 为 (名称, 公式)；
+    /// task 只决定交易所是否启用，subscribed 中已有的行情 key 被跳过。
+    /// 以 .bid/.ask/.mid 引用的 symbol 标记 book，需要额外订阅最优买卖价
+    pub async fn resolve_symbols(
+        config_repo: &ConfigRepository,
+        formulas: &[(&str, &str)],
+        enabled_exchanges: &HashSet<String>,
+        subscribed: &mut HashSet<String>,
+    ) -> anyhow::Result<HashMap<ExchangeEnum, Vec<Symbol>>> {
+        let mut symbols_map: HashMap<ExchangeEnum, Vec<Symbol>> = HashMap::new();
+        for &(name, formula) in formulas {
+            let expr = match formula::parse(formula) {
+                Ok(expr) => expr,
+                Err(e) => {
+                    warn!("name {} invalid formula {}: {}", name, formula, e);
+                    continue;
+                }
+            };
+            for source in expr.sources() {
+                let book = source.field != PriceField::Last;
+                if !subscribed.insert(source.key()) {
+                    if book
+                        && let Some(exchange) = ExchangeEnum::from_name(&source.exchange)
+                        && let Some(symbol) = symbols_map
+                            .get_mut(&exchange)
+                            .and_then(|symbols| symbols.iter_mut().find(|s| s.symbol_name == source.symbol))
+                    {
+                        symbol.book = true;
+                    }
+                    continue;
+                }
+                let Some(exchange) = ExchangeEnum::from_name(&source.exchange) else {
+                    warn!("name {} references unknown exchange {}", name, source.exchange);
+                    continue;
+                };
+                if !enabled_exchanges.contains(&source.exchange) {
+                    warn!("name {} references {} but exchange has no enabled task", name, source);
+                    continue;
+                }
+                match config_repo.get_symbol(&source.symbol, &source.exchange).await? {
+                    Some(symbol) => symbols_map.entry(exchange).or_default().push(Symbol { book, ..symbol }),
+                    None => warn!("name {} references unknown symbol {}", name, source),
+                }
+            }
+        }
+        Ok(symbols_map)
+    }
 }
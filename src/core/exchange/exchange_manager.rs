@@ -1,9 +1,9 @@
 use crate::core::ws::websocket_listener::WebSocketStatusListener;
 use crate::exchanges::ExchangeEnum;
 use dashmap::DashMap;
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::Arc};
 use tokio::time::{sleep, Duration};
-use tracing::info;
+use tracing::{error, info};
 
 /// ExchangeManager 管理多个 WebSocketStatusListener
 pub struct ExchangeManager {
@@ -17,13 +17,17 @@ impl ExchangeManager {
         }
     }
 
-    /// 添加交易所客户端并订阅初始 symbol
+    /// 添加交易所客户端并订阅初始 symbol，book_symbols 为其中需要最优买卖价的 symbol
     pub async fn add_exchange(
         &self,
         exchange: ExchangeEnum,
         client: Arc<dyn WebSocketStatusListener>,
         symbols: HashSet<String>,
+        book_symbols: HashSet<String>,
     ) {
+        if let Err(e) = client.subscribe_book(book_symbols).await {
+            error!("{} subscribe book error: {:?}", exchange.name(), e);
+        }
         // 先 clone 一份 Arc 用于 connect
         let client_clone = client.clone();
         let _ = client_clone.connect(Some(symbols.clone())).await;
@@ -33,6 +37,26 @@ impl ExchangeManager {
     }
 
 
+    /// 运行中追加订阅，symbols 为 third_symbol_name -> symbol_name，book_symbols 为其中需要最优买卖价的 symbol；
+    /// 该交易所没有客户端时返回 false
+    pub async fn subscribe(
+        &self,
+        exchange: &ExchangeEnum,
+        symbols: HashMap<String, String>,
+        book_symbols: HashSet<String>,
+    ) -> bool {
+        let Some(client) = self.get_client(exchange) else {
+            return false;
+        };
+        if let Err(e) = client.subscribe_book(book_symbols).await {
+            error!("{} subscribe book error: {:?}", exchange.name(), e);
+        }
+        if let Err(e) = client.subscribe(symbols).await {
+            error!("{} subscribe error: {:?}", exchange.name(), e);
+        }
+        true
+    }
+
     /// 获取客户端
     pub fn get_client(&self, exchange: &ExchangeEnum) -> Option<Arc<dyn WebSocketStatusListener>> {
         self.clients.get(exchange).map(|v| v.clone())
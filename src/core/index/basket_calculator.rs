@@ -0,0 +1,281 @@
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+/// 一篮子指数的成分，quantity 为当前持有的单位数量
+#[derive(Debug, Clone)]
+pub struct BasketHolding {
+    pub index_name: String,
+    pub quantity: Decimal,
+}
+
+/// 调仓目标：按权重或按数量，两者都有时以数量为准
+#[derive(Debug, Clone)]
+pub struct BasketTarget {
+    pub index_name: String,
+    pub weight: Option<Decimal>,
+    pub quantity: Option<Decimal>,
+}
+
+/// 一次调仓的结果
+#[derive(Debug, Clone)]
+pub struct BasketRebalance {
+    pub basket_name: String,
+    /// 调仓前的除数，首次建仓时为 None
+    pub old_divisor: Option<Decimal>,
+    pub new_divisor: Decimal,
+    /// 调仓时的指数点位，调仓前后保持不变
+    pub level: Decimal,
+    pub holdings: Vec<BasketHolding>,
+}
+
+/// 一次计算的篮子点位
+#[derive(Debug, Clone)]
+pub struct BasketLevel {
+    pub name: String,
+    pub level: Decimal,
+    pub formula: String,
+    /// 成分指数名称
+    pub constituents: Vec<String>,
+}
+
+/// 一篮子指数：level = Σ(quantity × 成分指数) / divisor，成分取自已有的单一资产指数。
+/// 调仓时按当前价格重新计算数量和除数，保证调仓前后点位连续
+#[derive(Debug, Clone)]
+pub struct BasketCalculator {
+    pub name: String,
+    /// 首次建仓时的点位
+    pub base_level: Decimal,
+    /// 尚未建仓时为 None
+    pub divisor: Option<Decimal>,
+    /// 当前持仓，尚未建仓时为空
+    pub holdings: Vec<BasketHolding>,
+    /// 最近一次调仓的目标，尚未建仓时用于首次建仓
+    pub targets: Vec<BasketTarget>,
+}
+
+impl BasketCalculator {
+    pub fn new(
+        name: String,
+        base_level: Decimal,
+        divisor: Option<Decimal>,
+        holdings: Vec<BasketHolding>,
+        targets: Vec<BasketTarget>,
+    ) -> Self {
+        Self {
+            name,
+            base_level,
+            divisor,
+            holdings,
+            targets,
+        }
+    }
+
+    /// 当前持仓的成分指数，尚未建仓时为调仓目标的成分
+    pub fn constituents(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        if self.holdings.is_empty() {
+            Box::new(self.targets.iter().map(|t| t.index_name.as_str()))
+        } else {
+            Box::new(self.holdings.iter().map(|h| h.index_name.as_str()))
+        }
+    }
+
+    /// 计算当前点位，尚未建仓或缺少成分价格时返回错误
+    pub fn level(&self, prices: &HashMap<String, Decimal>) -> anyhow::Result<Decimal> {
+        let divisor = match self.divisor {
+            Some(d) if !d.is_zero() => d,
+            _ => anyhow::bail!("basket {} has not been rebalanced", self.name),
+        };
+        if self.holdings.is_empty() {
+            anyhow::bail!("basket {} has no constituents", self.name);
+        }
+        Ok(notional(&self.holdings, prices)? / divisor)
+    }
+
+    /// 按目标调仓：权重成分的数量 = weight × level / price，除数 = 新组合市值 / level。
+    /// 尚未建仓时 level 取 base_level
+    pub fn rebalance(&mut self, targets: &[BasketTarget], prices: &HashMap<String, Decimal>) -> anyhow::Result<BasketRebalance> {
+        if targets.is_empty() {
+            anyhow::bail!("basket {} has no constituents", self.name);
+        }
+        let level = match self.divisor {
+            Some(_) => self.level(prices)?,
+            None => self.base_level,
+        };
+        if level <= Decimal::ZERO {
+            anyhow::bail!("basket {} level {} is not positive", self.name, level);
+        }
+
+        let mut holdings = Vec::with_capacity(targets.len());
+        for target in targets {
+            let quantity = match (target.quantity, target.weight) {
+                (Some(quantity), _) => quantity,
+                (None, Some(weight)) => {
+                    let price = price_of(prices, &target.index_name)?;
+                    weight * level / price
+                }
+                (None, None) => anyhow::bail!(
+                    "basket {} constituent {} has neither weight nor quantity",
+                    self.name, target.index_name
+                ),
+            };
+            holdings.push(BasketHolding {
+                index_name: target.index_name.clone(),
+                quantity,
+            });
+        }
+
+        let value = notional(&holdings, prices)?;
+        if value <= Decimal::ZERO {
+            anyhow::bail!("basket {} notional {} is not positive", self.name, value);
+        }
+        let new_divisor = value / level;
+        let old_divisor = self.divisor.replace(new_divisor);
+        self.holdings = holdings;
+        self.targets = targets.to_vec();
+
+        Ok(BasketRebalance {
+            basket_name: self.name.clone(),
+            old_divisor,
+            new_divisor,
+            level,
+            holdings: self.holdings.clone(),
+        })
+    }
+
+    /// 持久化时记录的公式，如 (0.5*BTCUSDT + 2*ETHUSDT) / 1.25
+    pub fn formula(&self) -> String {
+        let legs: Vec<String> = self
+            .holdings
+            .iter()
+            .map(|h| format!("{}*{}", h.quantity.normalize(), h.index_name))
+            .collect();
+        let divisor = self.divisor.map_or("?".to_string(), |d| d.normalize().to_string());
+        format!("({}) / {}", legs.join(" + "), divisor)
+    }
+}
+
+/// 持仓的持久化格式，如 BTCUSDT:0.01,ETHUSDT:0.2
+pub fn format_holdings(holdings: &[BasketHolding]) -> String {
+    holdings
+        .iter()
+        .map(|h| format!("{}:{}", h.index_name, h.quantity.normalize()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 解析 format_holdings 的结果
+pub fn parse_holdings(text: &str) -> anyhow::Result<Vec<BasketHolding>> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|item| {
+            let (index_name, quantity) = item
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("invalid basket holding {}", item))?;
+            Ok(BasketHolding {
+                index_name: index_name.trim().to_string(),
+                quantity: quantity.trim().parse()?,
+            })
+        })
+        .collect()
+}
+
+fn price_of(prices: &HashMap<String, Decimal>, index_name: &str) -> anyhow::Result<Decimal> {
+    match prices.get(index_name) {
+        Some(price) if *price > Decimal::ZERO => Ok(*price),
+        Some(price) => anyhow::bail!("index {} price {} is not positive", index_name, price),
+        None => anyhow::bail!("index {} has no published value", index_name),
+    }
+}
+
+fn notional(holdings: &[BasketHolding], prices: &HashMap<String, Decimal>) -> anyhow::Result<Decimal> {
+    let mut sum = Decimal::ZERO;
+    for h in holdings {
+        sum += h.quantity * price_of(prices, &h.index_name)?;
+    }
+    Ok(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn prices(btc: &str, eth: &str) -> HashMap<String, Decimal> {
+        HashMap::from([("BTCUSDT".to_string(), dec(btc)), ("ETHUSDT".to_string(), dec(eth))])
+    }
+
+    fn weight(index_name: &str, weight: &str) -> BasketTarget {
+        BasketTarget {
+            index_name: index_name.to_string(),
+            weight: Some(dec(weight)),
+            quantity: None,
+        }
+    }
+
+    fn basket(targets: Vec<BasketTarget>) -> BasketCalculator {
+        BasketCalculator::new("CRYPTO2".to_string(), dec("1000"), None, Vec::new(), targets)
+    }
+
+    #[test]
+    fn initial_rebalance_starts_at_base_level() {
+        let targets = vec![weight("BTCUSDT", "0.5"), weight("ETHUSDT", "0.5")];
+        let mut basket = basket(targets.clone());
+        assert!(basket.level(&prices("50000", "2500")).is_err());
+
+        let rebalance = basket.rebalance(&targets, &prices("50000", "2500")).unwrap();
+        assert_eq!(rebalance.old_divisor, None);
+        assert_eq!(rebalance.level, dec("1000"));
+        assert_eq!(basket.level(&prices("50000", "2500")).unwrap(), dec("1000"));
+        // BTC 上涨 10%，持仓一半的篮子上涨 5%
+        assert_eq!(basket.level(&prices("55000", "2500")).unwrap(), dec("1050"));
+    }
+
+    #[test]
+    fn rebalance_keeps_level_continuous() {
+        let mut basket = basket(vec![weight("BTCUSDT", "0.5"), weight("ETHUSDT", "0.5")]);
+        let targets = basket.targets.clone();
+        basket.rebalance(&targets, &prices("50000", "2500")).unwrap();
+
+        let moved = prices("60000", "2000");
+        let before = basket.level(&moved).unwrap();
+        let targets = vec![
+            weight("BTCUSDT", "0.8"),
+            BasketTarget {
+                index_name: "ETHUSDT".to_string(),
+                weight: None,
+                quantity: Some(dec("0.05")),
+            },
+        ];
+        let rebalance = basket.rebalance(&targets, &moved).unwrap();
+        let after = basket.level(&moved).unwrap();
+
+        assert_eq!(rebalance.level, before);
+        assert!((after - before).abs() < dec("0.000000000001"));
+        assert_ne!(rebalance.old_divisor, Some(rebalance.new_divisor));
+        assert_eq!(basket.holdings[1].quantity, dec("0.05"));
+    }
+
+    #[test]
+    fn rebalance_without_prices_keeps_current_state() {
+        let mut basket = basket(vec![weight("BTCUSDT", "1")]);
+        let targets = basket.targets.clone();
+        basket.rebalance(&targets, &prices("50000", "2500")).unwrap();
+        let divisor = basket.divisor;
+
+        let only_btc = HashMap::from([("BTCUSDT".to_string(), dec("50000"))]);
+        assert!(basket.rebalance(&[weight("ETHUSDT", "1")], &only_btc).is_err());
+        assert_eq!(basket.divisor, divisor);
+        assert_eq!(basket.holdings[0].index_name, "BTCUSDT");
+    }
+
+    #[test]
+    fn holdings_round_trip() {
+        let holdings = parse_holdings("BTCUSDT:0.01, ETHUSDT:0.20").unwrap();
+        assert_eq!(format_holdings(&holdings), "BTCUSDT:0.01,ETHUSDT:0.2");
+    }
+}
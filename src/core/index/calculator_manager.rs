@@ -1,26 +1,475 @@
-use crate::core::index::index_calculator::IndexCalculator;
+use crate::core::index::basket_calculator::{BasketCalculator, BasketLevel, BasketRebalance, BasketTarget};
+use crate::core::index::circuit_breaker::{BreakerEvent, BreakerOutcome};
+use crate::core::index::formula::{self, Quote};
+use crate::core::index::index_calculator::{Index, IndexCalculator, IndexSample, PriceEntry};
+use crate::core::index::mark_price_calculator::{MarkPrice, MarkPriceCalculator};
+use crate::core::model::{IndexConfig, QuoteConversion};
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::RwLock;
 use std::sync::Arc;
+use tracing::{debug, warn};
 
 pub type SharedCalculators = Arc<RwLock<HashMap<String, IndexCalculator>>>;
 
 pub struct CalculatorManager {
     pub calculators: SharedCalculators,
+    /// 按依赖关系排好的计算顺序，被 index(NAME) 引用的指数排在前面
+    pub order: RwLock<Vec<String>>,
+    /// 行情 key -> 公式中直接引用该行情的指数
+    pub routes: RwLock<HashMap<String, Vec<String>>>,
+    /// 标记价格计算器，名称不能与指数重复（路由共用）
+    pub marks: RwLock<HashMap<String, MarkPriceCalculator>>,
+    /// 一篮子指数，成分取自已发布的指数
+    pub baskets: RwLock<HashMap<String, BasketCalculator>>,
 }
 
 impl CalculatorManager {
-    pub fn new(calculators: HashMap<String, IndexCalculator>) -> Self {
+    pub fn new(
+        calculators: HashMap<String, IndexCalculator>,
+        order: Vec<String>,
+        routes: HashMap<String, Vec<String>>,
+    ) -> Self {
         Self {
             calculators: Arc::new(RwLock::new(calculators)),
+            order: RwLock::new(order),
+            routes: RwLock::new(routes),
+            marks: RwLock::new(HashMap::new()),
+            baskets: RwLock::new(HashMap::new()),
         }
     }
 
-    pub async fn update_price(&self, index_name: &str, key: &str, price: Decimal) {
+    pub fn with_marks(self, marks: HashMap<String, MarkPriceCalculator>) -> Self {
+        Self {
+            marks: RwLock::new(marks),
+            ..self
+        }
+    }
+
+    pub fn with_baskets(self, baskets: HashMap<String, BasketCalculator>) -> Self {
+        Self {
+            baskets: RwLock::new(baskets),
+            ..self
+        }
+    }
+
+    pub async fn order(&self) -> Vec<String> {
+        self.order.read().await.clone()
+    }
+
+    /// 公式版本切换后更新各计算器的公式并重新计算顺序和路由，pending 为尚未生效的公式，其引用的行情提前开始接收；
+    /// deps 为公式之外的依赖 (指数名称, 依赖的指数)，如 quote_conversion
+    pub async fn apply_formulas(
+        &self,
+        formulas: &[(&str, &str)],
+        pending: &[(&str, &str)],
+        deps: &[(&str, &str)],
+    ) -> anyhow::Result<()> {
+        let order = resolve_order(formulas, deps)?;
+        {
+            let mut calcs = self.calculators.write().await;
+            for &(name, formula) in formulas {
+                if let Some(calc) = calcs.get_mut(name) {
+                    calc.set_formula(formula);
+                }
+            }
+        }
+        // 不在持有 marks 锁时等待 routes 写锁，route_price 按 routes -> calculators -> marks 的顺序加锁
+        let mark_formulas: Vec<(String, String)> = self
+            .marks
+            .read()
+            .await
+            .values()
+            .map(|m| (m.name.clone(), m.perp_formula.clone()))
+            .collect();
+        let all: Vec<(&str, &str)> = formulas
+            .iter()
+            .chain(pending)
+            .copied()
+            .chain(mark_formulas.iter().map(|(name, formula)| (name.as_str(), formula.as_str())))
+            .collect();
+        let routes = resolve_routes(&all);
+        *self.order.write().await = order;
+        *self.routes.write().await = routes;
+        Ok(())
+    }
+
+    /// 运行中加入新指数的计算器，需随后调用 apply_formulas 更新计算顺序和路由
+    pub async fn add_calculator(&self, calculator: IndexCalculator) {
+        self.calculators.write().await.insert(calculator.index_name.clone(), calculator);
+    }
+
+    /// 将行情写入公式中引用它的计算器（包括换算腿，如 Binance.USDCUSDT），没有计算器引用时返回 false
+    pub async fn route_price(&self, key: &str, price: PriceEntry) -> bool {
+        let routes = self.routes.read().await;
+        let Some(names) = routes.get(key) else {
+            return false;
+        };
+        let mut calcs = self.calculators.write().await;
+        let mut marks = self.marks.write().await;
+        for name in names {
+            if let Some(calc) = calcs.get_mut(name) {
+                calc.update_price(key, price);
+            } else if let Some(mark) = marks.get_mut(name) {
+                mark.perp.update_price(key, price);
+            }
+        }
+        true
+    }
+
+    /// 标记价格的输入：(现货指数名称, 永续合约价格公式)
+    pub async fn mark_inputs(&self) -> Vec<(String, String)> {
+        let marks = self.marks.read().await;
+        marks
+            .values()
+            .map(|m| (m.index_name.clone(), m.perp_formula.clone()))
+            .collect()
+    }
+
+    /// 取某个标记价格 (from_ms, to_ms] 内溢价指数的时间加权平均及采样数
+    pub async fn premium_between(&self, mark_name: &str, from_ms: i64, to_ms: i64) -> Option<(Decimal, usize)> {
+        let marks = self.marks.read().await;
+        marks.get(mark_name)?.premium_between(from_ms, to_ms)
+    }
+
+    /// 用现货指数的发布值更新引用它的标记价格，按该指数的 scale 和 rounding 截取；
+    /// 降级或熔断时保持的值不应传入，否则会混入溢价历史和资金费率
+    pub async fn calculate_marks(&self, index_name: &str, now_ms: i64, index_price: Decimal) -> Vec<(String, MarkPrice)> {
+        let calcs = self.calculators.read().await;
+        let mut marks = self.marks.write().await;
+        let mut results = Vec::new();
+        for mark in marks.values_mut().filter(|m| m.index_name == index_name) {
+            let perp = mark
+                .perp
+                .calculate_index(&mark.name, now_ms as u64, &HashMap::new())
+                .map(|idx| idx.last);
+            if let Some(mut price) = mark.update(now_ms, index_price, perp) {
+                if let Some(calc) = calcs.get(index_name) {
+                    price.mark_price = calc.round(price.mark_price);
+                }
+                results.push((mark.name.clone(), price));
+            }
+        }
+        results
+    }
+
+    /// 记录本次发布的指数值并返回最新 EDP，last 为 None（保持的值）时只计算不记录
+    pub async fn calculate_edp(&self, index_name: &str, now_ms: i64, last: Option<Decimal>) -> Option<Decimal> {
+        let mut calcs = self.calculators.write().await;
+        calcs.get_mut(index_name)?.calculate_edp(now_ms, last)
+    }
+
+    /// 记录一次有效（非降级）的指数采样
+    pub async fn record_sample(&self, index_name: &str, now_ms: i64, idx: &Index) {
         let mut calcs = self.calculators.write().await;
         if let Some(calc) = calcs.get_mut(index_name) {
-            calc.update_price(key, price);
+            calc.record_sample(now_ms, idx.last, &idx.used_sources);
+        }
+    }
+
+    /// 取某个指数 (from_ms, to_ms] 内的时间加权平均及参与计算的采样
+    pub async fn twap_between(&self, index_name: &str, from_ms: i64, to_ms: i64) -> Option<(Decimal, Vec<IndexSample>)> {
+        let calcs = self.calculators.read().await;
+        calcs.get(index_name)?.twap_between(from_ms, to_ms)
+    }
+
+    /// 按该指数的 scale 和 rounding 截取小数位
+    pub async fn round(&self, index_name: &str, value: Decimal) -> Decimal {
+        let calcs = self.calculators.read().await;
+        calcs.get(index_name).map_or(value, |calc| calc.round(value))
+    }
+
+    /// 手动恢复熔断，未处于熔断状态时返回 None
+    pub async fn reset_breaker(&self, index_name: &str) -> Option<BreakerEvent> {
+        let mut calcs = self.calculators.write().await;
+        calcs.get_mut(index_name)?.breaker.reset()
+    }
+
+    /// 计算成分包含 changed 中任一指数的一篮子指数，prices 为各指数最近一次的发布值；
+    /// 缺少成分价格或尚未建仓的篮子被跳过
+    pub async fn calculate_baskets(
+        &self,
+        prices: &HashMap<String, Decimal>,
+        changed: &HashSet<String>,
+    ) -> Vec<BasketLevel> {
+        let baskets = self.baskets.read().await;
+        let mut results = Vec::new();
+        for basket in baskets.values() {
+            if !basket.constituents().any(|c| changed.contains(c)) {
+                continue;
+            }
+            match basket.level(prices) {
+                Ok(level) => results.push(BasketLevel {
+                    name: basket.name.clone(),
+                    level,
+                    formula: basket.formula(),
+                    constituents: basket.constituents().map(String::from).collect(),
+                }),
+                Err(e) => debug!("basket {} skipped: {}", basket.name, e),
+            }
+        }
+        results
+    }
+
+    /// 对成分包含 changed 中任一指数且尚未建仓的篮子按 base_level 试算首次建仓；
+    /// 不修改当前篮子，持久化成功后用 replace_basket 生效
+    pub async fn plan_initial_baskets(
+        &self,
+        prices: &HashMap<String, Decimal>,
+        changed: &HashSet<String>,
+    ) -> Vec<(BasketCalculator, BasketRebalance)> {
+        let baskets = self.baskets.read().await;
+        let mut planned = Vec::new();
+        for basket in baskets.values() {
+            if basket.divisor.is_some() || !basket.constituents().any(|c| changed.contains(c)) {
+                continue;
+            }
+            let mut basket = basket.clone();
+            let targets = basket.targets.clone();
+            match basket.rebalance(&targets, prices) {
+                Ok(rebalance) => planned.push((basket, rebalance)),
+                Err(e) => debug!("basket {} not initialized: {}", basket.name, e),
+            }
         }
+        planned
+    }
+
+    /// 按新的成分目标试算调仓，调仓前后点位不变；不修改当前篮子，持久化成功后用 replace_basket 生效
+    pub async fn plan_basket_rebalance(
+        &self,
+        basket_name: &str,
+        targets: &[BasketTarget],
+        prices: &HashMap<String, Decimal>,
+    ) -> anyhow::Result<(BasketCalculator, BasketRebalance)> {
+        let baskets = self.baskets.read().await;
+        let Some(basket) = baskets.get(basket_name) else {
+            anyhow::bail!("basket {} is not running", basket_name);
+        };
+        let mut basket = basket.clone();
+        let rebalance = basket.rebalance(targets, prices)?;
+        Ok((basket, rebalance))
+    }
+
+    /// 用调仓后的篮子替换当前篮子
+    pub async fn replace_basket(&self, basket: BasketCalculator) {
+        self.baskets.write().await.insert(basket.name.clone(), basket);
+    }
+
+    /// 按依赖顺序计算所有指数，前面指数的结果供后面的 index(NAME) 引用。
+    /// due 中的指数在满足 min_sources 时做熔断检查；未满足 min_sources 或熔断中的结果不对外可见，
+    /// 引用方使用 held 中最近一次有效发布的值
+    pub async fn calculate_all<'a>(
+        &self,
+        index_configs: &'a [IndexConfig],
+        time: u64,
+        due: &HashSet<String>,
+        held: &HashMap<String, Index>,
+    ) -> Vec<(&'a IndexConfig, Option<Index>, BreakerOutcome)> {
+        let order = self.order.read().await;
+        let mut calcs = self.calculators.write().await;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let mut indices: HashMap<String, Quote> = HashMap::new();
+        let mut results = Vec::with_capacity(order.len());
+
+        for name in order.iter() {
+            let (Some(config), Some(calc)) = (
+                index_configs.iter().find(|c| &c.name == name),
+                calcs.get_mut(name),
+            ) else {
+                continue;
+            };
+            let idx = calc.calculate_index(name, time, &indices);
+            // 启动时已校验并提示无法识别的配置，这里不再重复告警
+            let idx = match (idx, QuoteConversion::parse(config.quote_conversion.trim())) {
+                (Some(idx), Some(conversion)) => convert(idx, &conversion, &indices),
+                (idx, _) => idx,
+            };
+            // 只在发布前截取一次小数位，换算使用未截取的值
+            let idx = idx.map(|idx| Index {
+                last: calc.round(idx.last),
+                ..idx
+            });
+            let quorum_met = idx
+                .as_ref()
+                .is_some_and(|idx| idx.source_count >= config.min_sources.max(1) as usize);
+            let outcome = match &idx {
+                Some(idx) if quorum_met && due.contains(name) => calc.breaker.check(now_ms, idx.last),
+                _ => BreakerOutcome::default(),
+            };
+            let hold = outcome.hold || (!due.contains(name) && calc.breaker.is_tripped());
+            let visible = if quorum_met && !hold { idx.as_ref() } else { held.get(name) };
+            if let Some(idx) = visible {
+                indices.insert(
+                    name.clone(),
+                    Quote {
+                        price: idx.last,
+                        ts: idx.source_ts.unwrap_or(now_ms),
+                        volume: idx.volume,
+                    },
+                );
+            }
+            results.push((config, idx, outcome));
+        }
+        results
+    }
+}
+
+/// 按 quote_conversion 换算指数值，参考指数本轮没有结果时返回 None
+fn convert(mut idx: Index, conversion: &QuoteConversion, indices: &HashMap<String, Quote>) -> Option<Index> {
+    let raw = idx.last;
+    let (last, rate) = match conversion {
+        QuoteConversion::Multiply(index) | QuoteConversion::Divide(index) => {
+            let Some(rate) = indices.get(index).map(|q| q.price).filter(|p| !p.is_zero()) else {
+                warn!("name {} conversion index {} value not found", idx.symbol, index);
+                return None;
+            };
+            if matches!(conversion, QuoteConversion::Multiply(_)) {
+                idx.volume = idx.volume.map(|v| v * rate);
+                (raw * rate, Some(rate))
+            } else {
+                idx.volume = idx.volume.map(|v| v / rate);
+                (raw / rate, Some(rate))
+            }
+        }
+        QuoteConversion::Inverse => {
+            if raw.is_zero() {
+                warn!("name {} cannot invert zero", idx.symbol);
+                return None;
+            }
+            // 倒数后计价币变为原来的基础币，成交额无法换算
+            idx.volume = None;
+            (Decimal::ONE / raw, None)
+        }
+    };
+    idx.computed_formula = match rate {
+        Some(rate) => format!("{} ({} = {})", idx.computed_formula, conversion, rate),
+        None => format!("1 / ({})", idx.computed_formula),
+    };
+    idx.last = last;
+    idx.raw_last = Some(raw);
+    idx.conversion_rate = rate;
+    Some(idx)
+}
+
+/// 根据公式中的行情引用建立 行情 key -> 指数名称 的路由，formulas 为 (指数名称, 公式)
+pub fn resolve_routes(formulas: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+    let mut routes: HashMap<String, Vec<String>> = HashMap::new();
+    for &(name, formula) in formulas {
+        let Ok(expr) = formula::parse(formula) else {
+            continue;
+        };
+        for source in expr.sources() {
+            let names = routes.entry(source.key()).or_default();
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    routes
+}
+
+/// 各指数 quote_conversion 引用的参考指数 (指数名称, 参考指数)，参考指数需要先计算
+pub fn conversion_deps(configs: &[IndexConfig]) -> Vec<(&str, &str)> {
+    configs
+        .iter()
+        .filter(|c| QuoteConversion::parse(c.quote_conversion.trim()).is_some_and(|q| q.index_ref().is_some()))
+        .filter_map(|c| {
+            let (_, index) = c.quote_conversion.trim().split_once(':')?;
+            Some((c.name.as_str(), index.trim()))
+        })
+        .collect()
+}
+
+/// 根据公式中的 index(NAME) 引用对指数做拓扑排序，formulas 为 (指数名称, 公式)，
+/// extra_deps 为公式之外的依赖 (指数名称, 依赖的指数)，存在循环依赖时返回错误
+pub fn resolve_order(formulas: &[(&str, &str)], extra_deps: &[(&str, &str)]) -> anyhow::Result<Vec<String>> {
+    let names: HashSet<&str> = formulas.iter().map(|(name, _)| *name).collect();
+
+    // name -> 依赖的指数
+    let mut deps: HashMap<&str, Vec<String>> = HashMap::new();
+    for &(name, formula) in formulas {
+        let mut refs: Vec<String> = match formula::parse(formula) {
+            Ok(expr) => expr.index_refs().into_iter().map(String::from).collect(),
+            Err(e) => {
+                warn!("name {} invalid formula {}: {}", name, formula, e);
+                Vec::new()
+            }
+        };
+        refs.extend(extra_deps.iter().filter(|(n, _)| *n == name).map(|(_, dep)| dep.to_string()));
+        for r in &refs {
+            if !names.contains(r.as_str()) {
+                warn!("name {} references unknown index {}", name, r);
+            }
+        }
+        deps.insert(name, refs.into_iter().filter(|r| names.contains(r.as_str())).collect());
+    }
+
+    // Kahn 算法，保持配置原有顺序
+    let mut order: Vec<String> = Vec::with_capacity(formulas.len());
+    let mut done: HashSet<&str> = HashSet::new();
+    while order.len() < formulas.len() {
+        let ready: Vec<&str> = formulas
+            .iter()
+            .map(|(name, _)| *name)
+            .filter(|n| !done.contains(n))
+            .filter(|n| deps[n].iter().all(|d| done.contains(d.as_str())))
+            .collect();
+        if ready.is_empty() {
+            let cycle: Vec<&str> = formulas
+                .iter()
+                .map(|(name, _)| *name)
+                .filter(|n| !done.contains(n))
+                .collect();
+            anyhow::bail!("circular index dependency among {:?}", cycle);
+        }
+        for n in ready {
+            done.insert(n);
+            order.push(n.to_string());
+        }
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_dependencies_first() {
+        let formulas = [
+            ("ETHBTC", "index(ETHUSDT) / index(BTCUSDT)"),
+            ("ETHUSDT", "avg(Binance.ETHUSDT, Okex.ETH-USDT)"),
+            ("BTCUSDT", "avg(Binance.BTCUSDT, Okex.BTC-USDT)"),
+        ];
+        assert_eq!(resolve_order(&formulas, &[]).unwrap(), ["ETHUSDT", "BTCUSDT", "ETHBTC"]);
+    }
+
+    #[test]
+    fn orders_extra_deps_first() {
+        let formulas = [("BTCEUR", "Kraken.XBTUSD"), ("EURUSD", "Kraken.EURUSD")];
+        let order = resolve_order(&formulas, &[("BTCEUR", "EURUSD")]).unwrap();
+        assert_eq!(order, ["EURUSD", "BTCEUR"]);
+    }
+
+    #[test]
+    fn rejects_self_reference() {
+        let formulas = [("BTCUSDT", "index(BTCUSDT) * 1.01")];
+        assert!(resolve_order(&formulas, &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_direct_cycle() {
+        let formulas = [("A", "index(B)"), ("B", "index(A)"), ("C", "Binance.BTCUSDT")];
+        let err = resolve_order(&formulas, &[]).unwrap_err().to_string();
+        assert!(err.contains("\"A\"") && err.contains("\"B\"") && !err.contains("\"C\""));
+    }
+
+    #[test]
+    fn rejects_indirect_cycle() {
+        let formulas = [("A", "index(B) + 1"), ("B", "index(C) + 1"), ("C", "index(A) + 1")];
+        assert!(resolve_order(&formulas, &[]).is_err());
+        // 通过 quote_conversion 引用形成的循环同样被拒绝
+        let formulas = [("A", "index(B)"), ("B", "Binance.BTCUSDT")];
+        assert!(resolve_order(&formulas, &[("B", "A")]).is_err());
     }
 }
@@ -0,0 +1,316 @@
+use rust_decimal::Decimal;
+use std::collections::VecDeque;
+
+const MINUTE_MS: i64 = 60_000;
+
+/// 熔断事件类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerEventKind {
+    /// 触发熔断，指数保持上一次的值
+    Tripped,
+    /// 连续 confirm_ticks 次确认新价格后自动恢复
+    Resumed,
+    /// 手动恢复
+    Reset,
+}
+
+impl BreakerEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerEventKind::Tripped => "tripped",
+            BreakerEventKind::Resumed => "resumed",
+            BreakerEventKind::Reset => "reset",
+        }
+    }
+}
+
+/// 熔断原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerReason {
+    /// 单次计算变动超过 max_tick_move
+    TickMove,
+    /// 一分钟内变动超过 max_minute_move
+    MinuteMove,
+    /// 新价格已确认
+    Confirmed,
+    /// 人工操作
+    Manual,
+}
+
+impl BreakerReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerReason::TickMove => "tick_move",
+            BreakerReason::MinuteMove => "minute_move",
+            BreakerReason::Confirmed => "confirmed",
+            BreakerReason::Manual => "manual",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerEvent {
+    pub kind: BreakerEventKind,
+    pub reason: BreakerReason,
+    /// 熔断前最后一次发布的值
+    pub reference: Option<Decimal>,
+    /// 本次计算出的候选值
+    pub candidate: Option<Decimal>,
+    /// 相对变动比例
+    pub move_ratio: Option<Decimal>,
+}
+
+/// 单次检查的结果
+#[derive(Debug, Clone, Default)]
+pub struct BreakerOutcome {
+    /// 是否保持上一次的值（候选值不发布）
+    pub hold: bool,
+    pub event: Option<BreakerEvent>,
+}
+
+/// 熔断状态
+#[derive(Debug, Clone)]
+struct Trip {
+    reference: Decimal,
+    /// 熔断后最近一次候选值
+    last_candidate: Decimal,
+    /// 连续确认的次数
+    confirmed: u32,
+}
+
+/// 指数熔断器：候选值相对上一次发布值或一分钟内发布值的变动超过阈值时保持指数，
+/// 之后连续 confirm_ticks 次候选值之间的变动都在 max_tick_move 以内时恢复，也可手动恢复
+#[derive(Debug, Clone, Default)]
+pub struct CircuitBreaker {
+    /// 单次计算最大相对变动，0 表示不检查
+    pub max_tick_move: Decimal,
+    /// 一分钟内最大相对变动，0 表示不检查
+    pub max_minute_move: Decimal,
+    /// 自动恢复所需的确认次数，0 表示只能手动恢复
+    pub confirm_ticks: u32,
+    /// 最近一分钟内发布的值 (时间毫秒, 指数值)
+    history: VecDeque<(i64, Decimal)>,
+    trip: Option<Trip>,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_tick_move: Decimal, max_minute_move: Decimal, confirm_ticks: u32) -> Self {
+        Self {
+            max_tick_move,
+            max_minute_move,
+            confirm_ticks,
+            history: VecDeque::new(),
+            trip: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_tick_move > Decimal::ZERO || self.max_minute_move > Decimal::ZERO
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.trip.is_some()
+    }
+
+    /// 检查候选值，返回是否需要保持以及产生的事件
+    pub fn check(&mut self, now_ms: i64, candidate: Decimal) -> BreakerOutcome {
+        if !self.is_enabled() {
+            return BreakerOutcome::default();
+        }
+
+        if let Some(trip) = &mut self.trip {
+            let ratio = relative_move(trip.last_candidate, candidate);
+            if self.max_tick_move <= Decimal::ZERO || ratio <= self.max_tick_move {
+                trip.confirmed += 1;
+            } else {
+                trip.confirmed = 0;
+            }
+            trip.last_candidate = candidate;
+
+            if self.confirm_ticks == 0 || trip.confirmed < self.confirm_ticks {
+                return BreakerOutcome {
+                    hold: true,
+                    event: None,
+                };
+            }
+
+            let reference = trip.reference;
+            self.trip = None;
+            self.history.clear();
+            self.history.push_back((now_ms, candidate));
+            return BreakerOutcome {
+                hold: false,
+                event: Some(BreakerEvent {
+                    kind: BreakerEventKind::Resumed,
+                    reason: BreakerReason::Confirmed,
+                    reference: Some(reference),
+                    candidate: Some(candidate),
+                    move_ratio: Some(relative_move(reference, candidate)),
+                }),
+            };
+        }
+
+        let cutoff = now_ms - MINUTE_MS;
+        while self.history.front().is_some_and(|(ts, _)| *ts < cutoff) {
+            self.history.pop_front();
+        }
+
+        if let Some(&(_, last)) = self.history.back() {
+            let tick_ratio = relative_move(last, candidate);
+            let minute_ratio = self
+                .history
+                .iter()
+                .map(|(_, v)| relative_move(*v, candidate))
+                .max()
+                .unwrap_or(Decimal::ZERO);
+
+            let tripped = if self.max_tick_move > Decimal::ZERO && tick_ratio > self.max_tick_move {
+                Some((BreakerReason::TickMove, tick_ratio))
+            } else if self.max_minute_move > Decimal::ZERO && minute_ratio > self.max_minute_move {
+                Some((BreakerReason::MinuteMove, minute_ratio))
+            } else {
+                None
+            };
+
+            if let Some((reason, ratio)) = tripped {
+                self.trip = Some(Trip {
+                    reference: last,
+                    last_candidate: candidate,
+                    confirmed: 0,
+                });
+                return BreakerOutcome {
+                    hold: true,
+                    event: Some(BreakerEvent {
+                        kind: BreakerEventKind::Tripped,
+                        reason,
+                        reference: Some(last),
+                        candidate: Some(candidate),
+                        move_ratio: Some(ratio),
+                    }),
+                };
+            }
+        }
+
+        self.history.push_back((now_ms, candidate));
+        BreakerOutcome::default()
+    }
+
+    /// 手动恢复，下一次候选值直接发布并作为新的参考值
+    pub fn reset(&mut self) -> Option<BreakerEvent> {
+        let trip = self.trip.take()?;
+        self.history.clear();
+        Some(BreakerEvent {
+            kind: BreakerEventKind::Reset,
+            reason: BreakerReason::Manual,
+            reference: Some(trip.reference),
+            candidate: Some(trip.last_candidate),
+            move_ratio: Some(relative_move(trip.reference, trip.last_candidate)),
+        })
+    }
+}
+
+fn relative_move(from: Decimal, to: Decimal) -> Decimal {
+    if from.is_zero() {
+        return Decimal::ZERO;
+    }
+    ((to - from) / from).abs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(dec("0.05"), dec("0.08"), 3)
+    }
+
+    #[test]
+    fn trips_on_tick_move() {
+        let mut breaker = breaker();
+        assert!(!breaker.check(0, dec("100")).hold);
+        let outcome = breaker.check(1000, dec("110"));
+        assert!(outcome.hold);
+        let event = outcome.event.unwrap();
+        assert_eq!(event.kind, BreakerEventKind::Tripped);
+        assert_eq!(event.reason, BreakerReason::TickMove);
+        assert_eq!(event.reference, Some(dec("100")));
+        assert_eq!(event.move_ratio, Some(dec("0.1")));
+        assert!(breaker.is_tripped());
+    }
+
+    #[test]
+    fn trips_on_minute_move() {
+        let mut breaker = breaker();
+        for (i, price) in ["100", "104", "108"].iter().enumerate() {
+            assert!(!breaker.check(i as i64 * 10_000, dec(price)).hold);
+        }
+        // 每次变动都在 5% 以内，但相对一分钟内的 100 已超过 8%
+        let outcome = breaker.check(30_000, dec("112"));
+        assert!(outcome.hold);
+        let event = outcome.event.unwrap();
+        assert_eq!(event.reason, BreakerReason::MinuteMove);
+        assert_eq!(event.reference, Some(dec("108")));
+        assert_eq!(event.move_ratio, Some(dec("0.12")));
+    }
+
+    #[test]
+    fn minute_window_drops_old_values() {
+        let mut breaker = breaker();
+        breaker.check(0, dec("100"));
+        breaker.check(30_000, dec("104"));
+        breaker.check(61_000, dec("108"));
+        // 100 已移出一分钟窗口
+        assert!(!breaker.check(62_000, dec("112")).hold);
+    }
+
+    #[test]
+    fn resumes_after_confirm_ticks() {
+        let mut breaker = breaker();
+        breaker.check(0, dec("100"));
+        assert!(breaker.check(1000, dec("110")).hold);
+        assert!(breaker.check(2000, dec("111")).hold);
+        // 变动超过 max_tick_move，确认次数清零
+        assert!(breaker.check(3000, dec("120")).hold);
+        assert!(breaker.check(4000, dec("120")).hold);
+        assert!(breaker.check(5000, dec("121")).hold);
+        let outcome = breaker.check(6000, dec("121"));
+        assert!(!outcome.hold);
+        let event = outcome.event.unwrap();
+        assert_eq!(event.kind, BreakerEventKind::Resumed);
+        assert_eq!(event.reason, BreakerReason::Confirmed);
+        assert_eq!(event.reference, Some(dec("100")));
+        assert!(!breaker.is_tripped());
+        // 恢复后以新价格为参考
+        assert!(!breaker.check(7000, dec("122")).hold);
+    }
+
+    #[test]
+    fn zero_confirm_ticks_requires_manual_reset() {
+        let mut breaker = CircuitBreaker::new(dec("0.05"), Decimal::ZERO, 0);
+        breaker.check(0, dec("100"));
+        assert!(breaker.check(1000, dec("110")).hold);
+        for i in 2..10 {
+            assert!(breaker.check(i * 1000, dec("110")).hold);
+        }
+        let event = breaker.reset().unwrap();
+        assert_eq!(event.kind, BreakerEventKind::Reset);
+        assert_eq!(event.reason, BreakerReason::Manual);
+        assert_eq!(event.reference, Some(dec("100")));
+        assert_eq!(event.candidate, Some(dec("110")));
+        assert!(!breaker.is_tripped());
+        assert!(!breaker.check(11_000, dec("110")).hold);
+        assert!(breaker.reset().is_none());
+    }
+
+    #[test]
+    fn disabled_breaker_never_holds() {
+        let mut breaker = CircuitBreaker::default();
+        breaker.check(0, dec("100"));
+        assert!(!breaker.check(1000, dec("1000")).hold);
+    }
+}
@@ -0,0 +1,1455 @@
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// 行情引用的价格字段，例如 `Binance.BTCUSDT.mid`，不写时为最新成交价
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PriceField {
+    #[default]
+    Last,
+    Bid,
+    Ask,
+    /// 最优买卖价的中间价
+    Mid,
+}
+
+impl PriceField {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PriceField::Last => "last",
+            PriceField::Bid => "bid",
+            PriceField::Ask => "ask",
+            PriceField::Mid => "mid",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "last" => Some(PriceField::Last),
+            "bid" => Some(PriceField::Bid),
+            "ask" => Some(PriceField::Ask),
+            "mid" => Some(PriceField::Mid),
+            _ => None,
+        }
+    }
+}
+
+/// 公式中的行情引用，例如 `Binance.BTCUSDT`、`Okex.BTC-USDT`、`Binance.BTCUSDT.mid`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceRef {
+    pub exchange: String,
+    pub symbol: String,
+    pub field: PriceField,
+}
+
+impl SourceRef {
+    /// price_map 中使用的 key，格式为 "Exchange.SYMBOL"，不含价格字段
+    pub fn key(&self) -> String {
+        format!("{}.{}", self.exchange, self.symbol)
+    }
+}
+
+impl Display for SourceRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.field {
+            PriceField::Last => write!(f, "{}.{}", self.exchange, self.symbol),
+            field => write!(f, "{}.{}.{}", self.exchange, self.symbol, field.name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinaryOp {
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => 1,
+            BinaryOp::Mul | BinaryOp::Div => 2,
+        }
+    }
+}
+
+/// 聚合函数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    /// 等权平均：avg(a, b, ...)，缺失的行情不参与平均
+    Avg,
+    /// 加权平均：wavg(a:0.5, b:0.3, ...)，缺失的行情剔除后按剩余权重重新归一化
+    Wavg,
+    /// 中位数：median(a, b, ...)
+    Median,
+    /// 截尾平均：trimmed_mean(pct, a, b, ...)，两端各去掉 pct% 的值后平均，0 <= pct < 50
+    TrimmedMean,
+    /// 最大值与最小值的平均：mid_range(a, b, ...)
+    MidRange,
+    /// 成交额加权平均：vwap(a, b, ...)，按各参数 24h 成交额占比加权，没有成交额的参数不参与
+    Vwap,
+    /// 按优先级回退：first(a, b, ...)，取第一个可用且未过期的参数
+    First,
+}
+
+impl Function {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Avg => "avg",
+            Function::Wavg => "wavg",
+            Function::Median => "median",
+            Function::TrimmedMean => "trimmed_mean",
+            Function::MidRange => "mid_range",
+            Function::Vwap => "vwap",
+            Function::First => "first",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "avg" => Some(Function::Avg),
+            "wavg" => Some(Function::Wavg),
+            "median" => Some(Function::Median),
+            "trimmed_mean" => Some(Function::TrimmedMean),
+            "mid_range" => Some(Function::MidRange),
+            "vwap" => Some(Function::Vwap),
+            "first" => Some(Function::First),
+            _ => None,
+        }
+    }
+}
+
+/// 函数参数，weight 仅用于 wavg
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arg {
+    pub expr: Expr,
+    pub weight: Option<Decimal>,
+}
+
+/// 公式语法树
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(Decimal),
+    Source(SourceRef),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Arg>),
+    /// 引用其他指数的最新值：index(BTCUSDT)
+    Index(String),
+}
+
+/// 公式解析错误，position 为字符下标
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormulaError {
+    UnexpectedChar { ch: char, position: usize },
+    InvalidNumber { text: String, position: usize },
+    UnexpectedToken { token: String, position: usize },
+    UnexpectedEnd,
+    UnknownFunction { name: String, position: usize },
+    InvalidArguments { function: &'static str, reason: String },
+    /// 交易对后紧跟 '-数字'，无法区分是名称的一部分还是减法，例如 `Binance.BTCUSDT-1`
+    AmbiguousMinus { symbol: String, position: usize },
+}
+
+impl Display for FormulaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormulaError::UnexpectedChar { ch, position } => {
+                write!(f, "unexpected character '{}' at {}", ch, position)
+            }
+            FormulaError::InvalidNumber { text, position } => {
+                write!(f, "invalid number '{}' at {}", text, position)
+            }
+            FormulaError::UnexpectedToken { token, position } => {
+                write!(f, "unexpected token '{}' at {}", token, position)
+            }
+            FormulaError::UnexpectedEnd => write!(f, "unexpected end of formula"),
+            FormulaError::UnknownFunction { name, position } => {
+                write!(f, "unknown function '{}' at {}", name, position)
+            }
+            FormulaError::InvalidArguments { function, reason } => {
+                write!(f, "invalid arguments for {}: {}", function, reason)
+            }
+            FormulaError::AmbiguousMinus { symbol, position } => write!(
+                f,
+                "ambiguous '-' after symbol '{}' at {}, add spaces around '-' for subtraction",
+                symbol, position
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FormulaError {}
+
+/// 公式求值错误
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// 引用的行情不存在
+    MissingPrice(String),
+    /// 引用的行情已过期
+    StalePrice(String),
+    /// 引用的指数本轮没有计算结果
+    MissingIndex(String),
+    /// 聚合函数的所有参数都不可用
+    NoLiveSources(&'static str),
+    DivisionByZero,
+    Overflow,
+}
+
+impl EvalError {
+    /// 行情暂不可用（而不是公式本身有问题），聚合函数会剔除这类参数
+    pub fn is_unavailable(&self) -> bool {
+        matches!(
+            self,
+            EvalError::MissingPrice(_)
+                | EvalError::StalePrice(_)
+                | EvalError::MissingIndex(_)
+                | EvalError::NoLiveSources(_)
+        )
+    }
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::MissingPrice(key) => write!(f, "price not found for {}", key),
+            EvalError::StalePrice(key) => write!(f, "price is stale for {}", key),
+            EvalError::MissingIndex(name) => write!(f, "index value not found for {}", name),
+            EvalError::NoLiveSources(function) => write!(f, "no live sources for {}", function),
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+            EvalError::Overflow => write!(f, "decimal overflow"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// 行情报价，ts 为行情时间（毫秒），volume 为 24h 计价币成交额
+#[derive(Debug, Clone, Copy)]
+pub struct Quote {
+    pub price: Decimal,
+    pub ts: i64,
+    pub volume: Option<Decimal>,
+}
+
+/// 求值上下文：提供行情价格，并可在聚合前剔除异常参数
+pub trait EvalContext {
+    fn price(&self, source: &SourceRef) -> Option<Quote>;
+
+    /// 其他指数本轮的计算结果，ts 为该指数输入行情中最旧的时间
+    fn index(&self, _name: &str) -> Option<Quote> {
+        None
+    }
+
+    /// 行情时间为 ts 的数据是否已过期，默认不检查
+    fn is_stale(&self, _ts: i64) -> bool {
+        false
+    }
+
+    /// 返回聚合参数中需要剔除的下标，默认不剔除
+    fn reject_outliers(&self, _values: &[Decimal]) -> Vec<usize> {
+        Vec::new()
+    }
+}
+
+/// 求值结果，computed 为代入实际价格后的表达式，missing / stale 为聚合时被剔除的缺失 / 过期行情，
+/// excluded 为聚合时因偏离过大被剔除的参数
+#[derive(Debug, Clone)]
+pub struct Evaluated {
+    pub value: Decimal,
+    pub computed: String,
+    /// 参与计算的有效行情数：加减累加，乘除（汇率换算）计为一个，聚合函数的每个有效参数计为一个
+    pub sources: usize,
+    /// 参与计算的行情中最旧的时间（毫秒），不含行情时为 None
+    pub ts: Option<i64>,
+    /// 实际参与计算的行情，例如 "Binance.BTCUSDT"、"index(EURUSDT)"
+    pub used: Vec<String>,
+    /// 24h 计价币成交额，用于 vwap 加权；换算腿按汇率折算，加减运算结果为 None
+    pub volume: Option<Decimal>,
+    pub missing: Vec<String>,
+    pub stale: Vec<String>,
+    pub excluded: Vec<String>,
+    /// computed 最外层运算的优先级，用于决定外层是否需要加括号
+    precedence: u8,
+}
+
+impl Evaluated {
+    fn new(value: Decimal, computed: String, sources: usize, ts: Option<i64>, used: Vec<String>) -> Self {
+        Self {
+            value,
+            computed,
+            sources,
+            ts,
+            used,
+            volume: None,
+            missing: Vec::new(),
+            stale: Vec::new(),
+            excluded: Vec::new(),
+            precedence: ATOM,
+        }
+    }
+}
+
+// ------------------ tokenizer ------------------
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Decimal),
+    Source(SourceRef),
+    Ident(String),
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LParen,
+    RParen,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Source(s) => write!(f, "{}", s),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Comma => write!(f, ","),
+            Token::Colon => write!(f, ":"),
+            Token::Plus => write!(f, "+"),
+            Token::Minus => write!(f, "-"),
+            Token::Star => write!(f, "*"),
+            Token::Slash => write!(f, "/"),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+        }
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, FormulaError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '+' => tokens.push((Token::Plus, start)),
+            '-' => tokens.push((Token::Minus, start)),
+            '*' => tokens.push((Token::Star, start)),
+            '/' => tokens.push((Token::Slash, start)),
+            '(' => tokens.push((Token::LParen, start)),
+            ')' => tokens.push((Token::RParen, start)),
+            ',' => tokens.push((Token::Comma, start)),
+            ':' => tokens.push((Token::Colon, start)),
+            c if c.is_ascii_digit() || c == '.' => {
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let number = Decimal::from_str(&text)
+                    .map_err(|_| FormulaError::InvalidNumber { text, position: start })?;
+                tokens.push((Token::Number(number), start));
+                continue;
+            }
+            c if is_ident_start(c) => {
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                let exchange: String = chars[start..i].iter().collect();
+                if i >= chars.len() || chars[i] != '.' {
+                    tokens.push((Token::Ident(exchange), start));
+                    continue;
+                }
+                i += 1;
+                let symbol_start = i;
+                i = scan_symbol(&chars, i)?;
+                if i == symbol_start {
+                    return match chars.get(i) {
+                        Some(&ch) => Err(FormulaError::UnexpectedChar { ch, position: i }),
+                        None => Err(FormulaError::UnexpectedEnd),
+                    };
+                }
+                let symbol: String = chars[symbol_start..i].iter().collect();
+                let field = match scan_field(&chars, i) {
+                    Some((field, end)) => {
+                        i = end;
+                        field
+                    }
+                    None if chars.get(i) == Some(&'.') => {
+                        return Err(FormulaError::UnexpectedChar { ch: '.', position: i });
+                    }
+                    None => PriceField::Last,
+                };
+                tokens.push((Token::Source(SourceRef { exchange, symbol, field }), start));
+                continue;
+            }
+            ch => return Err(FormulaError::UnexpectedChar { ch, position: start }),
+        }
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+/// 扫描交易对后的价格字段 `.bid` / `.ask` / `.mid` / `.last`，返回字段和结束位置
+fn scan_field(chars: &[char], i: usize) -> Option<(PriceField, usize)> {
+    if chars.get(i) != Some(&'.') {
+        return None;
+    }
+    let mut j = i + 1;
+    while j < chars.len() && is_ident_char(chars[j]) {
+        j += 1;
+    }
+    if chars.get(j) == Some(&'.') {
+        return None;
+    }
+    let name: String = chars[i + 1..j].iter().collect();
+    PriceField::from_name(&name).map(|field| (field, j))
+}
+
+/// 扫描交易对名称。OKX 的交易对带 '-'（BTC-USDT），因此 '-' 后紧跟字母数字时视为名称的一部分；
+/// 但如果这一段后面是 '.' 且不是价格字段（例如 `Binance.BTCUSDT-Okex.BTC-USDT`），说明它是下一个引用，'-' 为减号。
+/// 不带 '-' 的名称后紧跟纯数字段（`Binance.BTCUSDT-1`）无法判断是否为减法，返回错误，减法需要在 '-' 两侧加空格；
+/// 已带 '-' 的名称允许纯数字段，如 OKX 交割合约 BTC-USD-240927
+fn scan_symbol(chars: &[char], start: usize) -> Result<usize, FormulaError> {
+    let mut i = start;
+    while i < chars.len() && is_ident_char(chars[i]) {
+        i += 1;
+    }
+    while i < chars.len() && chars[i] == '-' {
+        let mut j = i + 1;
+        while j < chars.len() && is_ident_char(chars[j]) {
+            j += 1;
+        }
+        if j == i + 1 || (chars.get(j) == Some(&'.') && scan_field(chars, j).is_none()) {
+            break;
+        }
+        let segment_is_number = chars[i + 1..j].iter().all(|c| c.is_ascii_digit());
+        if segment_is_number && !chars[start..i].contains(&'-') {
+            return Err(FormulaError::AmbiguousMinus {
+                symbol: chars[start..i].iter().collect(),
+                position: i,
+            });
+        }
+        i = j;
+    }
+    Ok(i)
+}
+
+// ------------------ parser ------------------
+
+/// 解析公式，支持 `+ - * /`、一元负号、括号、数字常量、`Exchange.SYMBOL` 引用和聚合函数
+pub fn parse(input: &str) -> Result<Expr, FormulaError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.parse_expr()?;
+    if let Some((token, position)) = parser.tokens.get(parser.pos) {
+        return Err(FormulaError::UnexpectedToken {
+            token: token.to_string(),
+            position: *position,
+        });
+    }
+    Ok(expr)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Option<(Token, usize)> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn parse_expr(&mut self) -> Result<Expr, FormulaError> {
+        let mut left = self.parse_term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => BinaryOp::Add,
+                Some(Token::Minus) => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_term()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    // term := unary (('*' | '/') unary)*
+    fn parse_term(&mut self) -> Result<Expr, FormulaError> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Star) => BinaryOp::Mul,
+                Some(Token::Slash) => BinaryOp::Div,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_unary()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    // unary := '-' unary | primary
+    fn parse_unary(&mut self) -> Result<Expr, FormulaError> {
+        if self.peek() == Some(&Token::Minus) {
+            self.pos += 1;
+            let operand = self.parse_unary()?;
+            return Ok(Expr::Neg(Box::new(operand)));
+        }
+        self.parse_primary()
+    }
+
+    // primary := number | source | call | '(' expr ')'
+    fn parse_primary(&mut self) -> Result<Expr, FormulaError> {
+        match self.next() {
+            Some((Token::Number(n), _)) => Ok(Expr::Number(n)),
+            Some((Token::Source(s), _)) => Ok(Expr::Source(s)),
+            Some((Token::Ident(name), _)) if name.eq_ignore_ascii_case("index") => self.parse_index_ref(),
+            Some((Token::Ident(name), position)) => self.parse_call(name, position),
+            Some((Token::LParen, _)) => {
+                let expr = self.parse_expr()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some((token, position)) => Err(FormulaError::UnexpectedToken {
+                token: token.to_string(),
+                position,
+            }),
+            None => Err(FormulaError::UnexpectedEnd),
+        }
+    }
+
+    // index_ref := 'index' '(' ident ')'
+    fn parse_index_ref(&mut self) -> Result<Expr, FormulaError> {
+        self.expect(Token::LParen)?;
+        let name = match self.next() {
+            Some((Token::Ident(name), _)) => name,
+            Some((token, position)) => {
+                return Err(FormulaError::UnexpectedToken {
+                    token: token.to_string(),
+                    position,
+                });
+            }
+            None => return Err(FormulaError::UnexpectedEnd),
+        };
+        self.expect(Token::RParen)?;
+        Ok(Expr::Index(name))
+    }
+
+    // call := ident '(' arg (',' arg)* ')'
+    // arg  := expr (':' number)?
+    fn parse_call(&mut self, name: String, position: usize) -> Result<Expr, FormulaError> {
+        let function = Function::from_name(&name)
+            .ok_or(FormulaError::UnknownFunction { name, position })?;
+        self.expect(Token::LParen)?;
+
+        let mut args = Vec::new();
+        loop {
+            let expr = self.parse_expr()?;
+            let weight = if self.peek() == Some(&Token::Colon) {
+                self.pos += 1;
+                match self.next() {
+                    Some((Token::Number(n), _)) => Some(n),
+                    Some((token, position)) => {
+                        return Err(FormulaError::UnexpectedToken {
+                            token: token.to_string(),
+                            position,
+                        });
+                    }
+                    None => return Err(FormulaError::UnexpectedEnd),
+                }
+            } else {
+                None
+            };
+            args.push(Arg { expr, weight });
+
+            match self.next() {
+                Some((Token::Comma, _)) => continue,
+                Some((Token::RParen, _)) => break,
+                Some((token, position)) => {
+                    return Err(FormulaError::UnexpectedToken {
+                        token: token.to_string(),
+                        position,
+                    });
+                }
+                None => return Err(FormulaError::UnexpectedEnd),
+            }
+        }
+
+        validate_call(function, &args)?;
+        Ok(Expr::Call(function, args))
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), FormulaError> {
+        match self.next() {
+            Some((token, _)) if token == expected => Ok(()),
+            Some((token, position)) => Err(FormulaError::UnexpectedToken {
+                token: token.to_string(),
+                position,
+            }),
+            None => Err(FormulaError::UnexpectedEnd),
+        }
+    }
+}
+
+fn validate_call(function: Function, args: &[Arg]) -> Result<(), FormulaError> {
+    let invalid = |reason: &str| FormulaError::InvalidArguments {
+        function: function.name(),
+        reason: reason.to_string(),
+    };
+    if function != Function::Wavg && args.iter().any(|a| a.weight.is_some()) {
+        return Err(invalid("weights are only allowed in wavg"));
+    }
+    match function {
+        Function::Avg | Function::Median | Function::MidRange | Function::Vwap | Function::First => {}
+        Function::TrimmedMean => {
+            let pct = match args.first().map(|a| &a.expr) {
+                Some(Expr::Number(pct)) => *pct,
+                _ => return Err(invalid("first argument must be the trim percentage")),
+            };
+            if pct < Decimal::ZERO || pct >= Decimal::from(50) {
+                return Err(invalid("trim percentage must be in [0, 50)"));
+            }
+            if args.len() < 2 {
+                return Err(invalid("at least one source is required"));
+            }
+        }
+        Function::Wavg => {
+            for arg in args {
+                match arg.weight {
+                    None => return Err(invalid(&format!("missing weight for {}", arg.expr))),
+                    Some(w) if w <= Decimal::ZERO => {
+                        return Err(invalid(&format!("weight of {} must be positive", arg.expr)));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// ------------------ evaluation ------------------
+
+impl Expr {
+    /// 公式引用的全部行情（按出现顺序，可能重复）
+    pub fn sources(&self) -> Vec<&SourceRef> {
+        let mut out = Vec::new();
+        self.collect_sources(&mut out);
+        out
+    }
+
+    fn collect_sources<'a>(&'a self, out: &mut Vec<&'a SourceRef>) {
+        match self {
+            Expr::Number(_) | Expr::Index(_) => {}
+            Expr::Source(s) => out.push(s),
+            Expr::Neg(inner) => inner.collect_sources(out),
+            Expr::Binary(_, l, r) => {
+                l.collect_sources(out);
+                r.collect_sources(out);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.expr.collect_sources(out);
+                }
+            }
+        }
+    }
+
+    /// 在聚合函数之外用 '+' 相加的含行情的项数，例如 `(A.X + B.X) / 2` 为 2、`avg(A.X, B.X)` 为 1。
+    /// 大于 1 时任一行情缺失都会导致整个公式失败，也不会做偏离剔除，应改用 avg(...) 等聚合函数
+    pub fn summed_source_terms(&self) -> usize {
+        match self {
+            Expr::Binary(BinaryOp::Add, l, r) => l.summed_source_terms() + r.summed_source_terms(),
+            Expr::Binary(BinaryOp::Mul | BinaryOp::Div, l, r) if matches!(**r, Expr::Number(_)) => {
+                l.summed_source_terms()
+            }
+            Expr::Binary(BinaryOp::Mul, l, r) if matches!(**l, Expr::Number(_)) => r.summed_source_terms(),
+            Expr::Neg(inner) => inner.summed_source_terms(),
+            expr => usize::from(!expr.sources().is_empty()),
+        }
+    }
+
+    /// 公式引用的其他指数名称（去重）
+    pub fn index_refs(&self) -> Vec<&str> {
+        let mut out = Vec::new();
+        self.collect_index_refs(&mut out);
+        out
+    }
+
+    fn collect_index_refs<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) | Expr::Source(_) => {}
+            Expr::Index(name) => {
+                if !out.contains(&name.as_str()) {
+                    out.push(name);
+                }
+            }
+            Expr::Neg(inner) => inner.collect_index_refs(out),
+            Expr::Binary(_, l, r) => {
+                l.collect_index_refs(out);
+                r.collect_index_refs(out);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.expr.collect_index_refs(out);
+                }
+            }
+        }
+    }
+
+    /// 在给定上下文中求值
+    pub fn evaluate<C: EvalContext + ?Sized>(&self, ctx: &C) -> Result<Evaluated, EvalError> {
+        match self {
+            Expr::Number(n) => Ok(Evaluated::new(*n, n.to_string(), 0, None, Vec::new())),
+            Expr::Source(s) => {
+                let quote = ctx.price(s).ok_or_else(|| EvalError::MissingPrice(s.to_string()))?;
+                if ctx.is_stale(quote.ts) {
+                    return Err(EvalError::StalePrice(s.to_string()));
+                }
+                Ok(Evaluated {
+                    volume: quote.volume,
+                    ..Evaluated::new(quote.price, quote.price.to_string(), 1, Some(quote.ts), vec![s.to_string()])
+                })
+            }
+            Expr::Index(name) => {
+                let quote = ctx.index(name).ok_or_else(|| EvalError::MissingIndex(name.clone()))?;
+                if ctx.is_stale(quote.ts) {
+                    return Err(EvalError::StalePrice(format!("index({})", name)));
+                }
+                let used = vec![format!("index({})", name)];
+                Ok(Evaluated::new(quote.price, quote.price.to_string(), 1, Some(quote.ts), used))
+            }
+            Expr::Neg(inner) => {
+                let v = inner.evaluate(ctx)?;
+                Ok(Evaluated {
+                    value: -v.value,
+                    computed: format!("-{}", wrap(v.precedence, NEG, &v.computed)),
+                    sources: v.sources,
+                    ts: v.ts,
+                    used: v.used,
+                    volume: v.volume,
+                    missing: v.missing,
+                    stale: v.stale,
+                    excluded: v.excluded,
+                    precedence: NEG,
+                })
+            }
+            Expr::Binary(op, l, r) => {
+                let lv = l.evaluate(ctx)?;
+                let rv = r.evaluate(ctx)?;
+                let value = match op {
+                    BinaryOp::Add => lv.value.checked_add(rv.value),
+                    BinaryOp::Sub => lv.value.checked_sub(rv.value),
+                    BinaryOp::Mul => lv.value.checked_mul(rv.value),
+                    BinaryOp::Div => {
+                        if rv.value.is_zero() {
+                            return Err(EvalError::DivisionByZero);
+                        }
+                        lv.value.checked_div(rv.value)
+                    }
+                }
+                .ok_or(EvalError::Overflow)?;
+                let (lp, rp) = child_precedence(*op);
+                let mut missing = lv.missing;
+                missing.extend(rv.missing);
+                let mut stale = lv.stale;
+                stale.extend(rv.stale);
+                let mut excluded = lv.excluded;
+                excluded.extend(rv.excluded);
+                Ok(Evaluated {
+                    value,
+                    computed: format!(
+                        "{} {} {}",
+                        wrap(lv.precedence, lp, &lv.computed),
+                        op.symbol(),
+                        wrap(rv.precedence, rp, &rv.computed)
+                    ),
+                    // A * B 这类换算得到的合成行情计为一个
+                    sources: match op {
+                        BinaryOp::Add | BinaryOp::Sub => lv.sources + rv.sources,
+                        BinaryOp::Mul | BinaryOp::Div => lv.sources.max(rv.sources),
+                    },
+                    ts: oldest(lv.ts, rv.ts),
+                    used: [lv.used, rv.used].concat(),
+                    // 换算腿的成交额按汇率折算为结果的计价币
+                    volume: match op {
+                        BinaryOp::Add | BinaryOp::Sub => None,
+                        BinaryOp::Mul => lv.volume.and_then(|v| v.checked_mul(rv.value)),
+                        BinaryOp::Div => lv.volume.and_then(|v| v.checked_div(rv.value)),
+                    },
+                    missing,
+                    stale,
+                    excluded,
+                    precedence: op.precedence(),
+                })
+            }
+            Expr::Call(function, args) => evaluate_call(*function, args, ctx),
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary(op, _, _) => op.precedence(),
+            Expr::Neg(_) => NEG,
+            Expr::Number(_) | Expr::Source(_) | Expr::Call(_, _) | Expr::Index(_) => ATOM,
+        }
+    }
+}
+
+/// 一元负号与原子表达式（数字、行情引用、函数调用）的优先级，二元运算见 BinaryOp::precedence
+const NEG: u8 = 3;
+const ATOM: u8 = 4;
+
+/// 已求值的聚合参数
+struct Leg {
+    /// 参数在公式中的原文，例如 "Binance.BTCUSDT"
+    label: String,
+    value: Decimal,
+    computed: String,
+    weight: Decimal,
+    sources: usize,
+    ts: Option<i64>,
+    used: Vec<String>,
+    volume: Option<Decimal>,
+    /// 是否参与偏离剔除：含交易所行情的参数参与（包括 Kraken.XBTEUR * index(EURUSDT) 这样的换算腿），
+    /// 单独的 index() 引用和数字常量不参与
+    checked: bool,
+}
+
+/// 聚合函数求值：逐个参数求值，行情不可用的参数被剔除（记录在 missing / stale 中），
+/// 再由上下文剔除偏离过大的参数（记录在 excluded 中），其余参与聚合
+fn evaluate_call<C: EvalContext + ?Sized>(
+    function: Function,
+    args: &[Arg],
+    ctx: &C,
+) -> Result<Evaluated, EvalError> {
+    if function == Function::First {
+        return evaluate_first(args, ctx);
+    }
+
+    // trimmed_mean 的第一个参数是截尾比例，不参与聚合
+    let (trim_pct, args) = match (function, args.split_first()) {
+        (Function::TrimmedMean, Some((Arg { expr: Expr::Number(pct), .. }, rest))) => (*pct, rest),
+        _ => (Decimal::ZERO, args),
+    };
+
+    let mut legs = Vec::with_capacity(args.len());
+    let mut missing = Vec::new();
+    let mut stale = Vec::new();
+    let mut excluded = Vec::new();
+    for arg in args {
+        match arg.expr.evaluate(ctx) {
+            Ok(v) => {
+                missing.extend(v.missing);
+                stale.extend(v.stale);
+                excluded.extend(v.excluded);
+                legs.push(Leg {
+                    label: arg.expr.to_string(),
+                    value: v.value,
+                    computed: v.computed,
+                    weight: arg.weight.unwrap_or(Decimal::ONE),
+                    sources: v.sources,
+                    ts: v.ts,
+                    used: v.used,
+                    volume: v.volume,
+                    checked: !arg.expr.sources().is_empty(),
+                });
+            }
+            Err(EvalError::MissingPrice(key)) => missing.push(key),
+            Err(EvalError::StalePrice(key)) => stale.push(key),
+            Err(EvalError::MissingIndex(name)) => missing.push(format!("index({})", name)),
+            Err(e) if e.is_unavailable() => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    if legs.is_empty() {
+        return Err(EvalError::NoLiveSources(function.name()));
+    }
+
+    // 只在交易所行情之间做偏离剔除，不同指数之间（如 BTC 与 ETH）的价格本来就不可比
+    let checked: Vec<usize> = (0..legs.len()).filter(|&i| legs[i].checked).collect();
+    let values: Vec<Decimal> = checked.iter().map(|&i| legs[i].value).collect();
+    let rejected: Vec<usize> = ctx
+        .reject_outliers(&values)
+        .into_iter()
+        .filter_map(|i| checked.get(i).copied())
+        .collect();
+    if !rejected.is_empty() && rejected.len() < checked.len() {
+        let mut i = 0;
+        legs.retain(|leg| {
+            let keep = !rejected.contains(&i);
+            if !keep {
+                excluded.push(leg.label.clone());
+            }
+            i += 1;
+            keep
+        });
+    }
+
+    // vwap 以成交额为权重，没有成交额的参数不参与
+    let mut no_volume = Vec::new();
+    if function == Function::Vwap {
+        legs.retain_mut(|leg| match leg.volume {
+            Some(volume) if volume > Decimal::ZERO => {
+                leg.weight = volume;
+                true
+            }
+            _ => {
+                no_volume.push(leg.label.clone());
+                false
+            }
+        });
+        if legs.is_empty() {
+            return Err(EvalError::NoLiveSources(function.name()));
+        }
+    }
+
+    let leg_count = legs.len();
+    let sources = legs.iter().filter(|l| l.sources > 0).count();
+    let ts = legs.iter().fold(None, |acc, l| oldest(acc, l.ts));
+    let used: Vec<String> = legs.iter().flat_map(|l| l.used.iter().cloned()).collect();
+    let volume = legs.iter().try_fold(Decimal::ZERO, |acc, l| acc.checked_add(l.volume?));
+    let (value, computed) = match function {
+        Function::Avg => average(&legs)?,
+        Function::Wavg => weighted_average("wavg(", &legs)?,
+        Function::Vwap => {
+            let (value, mut computed) = weighted_average("vwap(", &legs)?;
+            if !no_volume.is_empty() {
+                computed.pop();
+                computed.push_str(&format!(" | no volume: {})", no_volume.join(", ")));
+            }
+            (value, computed)
+        }
+        Function::Median => median(legs)?,
+        Function::TrimmedMean => trimmed_mean(trim_pct, legs)?,
+        Function::MidRange => mid_range(legs)?,
+        Function::First => unreachable!("first is evaluated by evaluate_first"),
+    };
+    // avg 展开为 "(a + b) / n"，其余函数保持函数形式
+    let precedence = if function == Function::Avg && leg_count > 1 {
+        BinaryOp::Div.precedence()
+    } else {
+        ATOM
+    };
+    Ok(Evaluated {
+        value,
+        computed,
+        sources,
+        ts,
+        used,
+        volume,
+        missing,
+        stale,
+        excluded,
+        precedence,
+    })
+}
+
+/// 按顺序求值，返回第一个可用的参数；排在前面但不可用的参数记录在 missing / stale 中，
+/// computed 展示为 "first(选中的参数 = 值 | skipped: 跳过的参数)"。选中后不再求值后面的参数，也不做偏离剔除
+fn evaluate_first<C: EvalContext + ?Sized>(args: &[Arg], ctx: &C) -> Result<Evaluated, EvalError> {
+    let mut missing = Vec::new();
+    let mut stale = Vec::new();
+    let mut skipped = Vec::new();
+    for arg in args {
+        let label = arg.expr.to_string();
+        match arg.expr.evaluate(ctx) {
+            Ok(mut v) => {
+                missing.append(&mut v.missing);
+                stale.append(&mut v.stale);
+                let mut computed = format!("first({} = {}", label, v.computed);
+                if !skipped.is_empty() {
+                    computed.push_str(&format!(" | skipped: {}", skipped.join(", ")));
+                }
+                computed.push(')');
+                return Ok(Evaluated {
+                    value: v.value,
+                    computed,
+                    sources: v.sources,
+                    ts: v.ts,
+                    used: v.used,
+                    volume: v.volume,
+                    missing,
+                    stale,
+                    excluded: v.excluded,
+                    precedence: ATOM,
+                });
+            }
+            Err(EvalError::MissingPrice(key)) => missing.push(key),
+            Err(EvalError::StalePrice(key)) => stale.push(key),
+            Err(EvalError::MissingIndex(name)) => missing.push(format!("index({})", name)),
+            Err(e) if e.is_unavailable() => {}
+            Err(e) => return Err(e),
+        }
+        skipped.push(label);
+    }
+    Err(EvalError::NoLiveSources(Function::First.name()))
+}
+
+fn oldest(a: Option<i64>, b: Option<i64>) -> Option<i64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// 用实际存在价格的数量平均
+fn average(legs: &[Leg]) -> Result<(Decimal, String), EvalError> {
+    let mut sum = Decimal::ZERO;
+    for leg in legs {
+        sum = sum.checked_add(leg.value).ok_or(EvalError::Overflow)?;
+    }
+    let count = legs.len();
+    let value = sum / Decimal::from(count);
+    let parts: Vec<&str> = legs.iter().map(|l| l.computed.as_str()).collect();
+    let computed = if count > 1 {
+        format!("({}) / {}", parts.join(" + "), count)
+    } else {
+        parts.join(" + ")
+    };
+    Ok((value, computed))
+}
+
+/// 按剩余权重重新归一化后加权平均，computed 展示实际使用的权重
+fn weighted_average(prefix: &str, legs: &[Leg]) -> Result<(Decimal, String), EvalError> {
+    let mut total_weight = Decimal::ZERO;
+    let mut weighted_sum = Decimal::ZERO;
+    for leg in legs {
+        total_weight = total_weight.checked_add(leg.weight).ok_or(EvalError::Overflow)?;
+        let term = leg.value.checked_mul(leg.weight).ok_or(EvalError::Overflow)?;
+        weighted_sum = weighted_sum.checked_add(term).ok_or(EvalError::Overflow)?;
+    }
+    let value = weighted_sum / total_weight;
+    let parts: Vec<String> = legs
+        .iter()
+        .map(|l| format!("{}:{}", l.computed, (l.weight / total_weight).round_dp(8).normalize()))
+        .collect();
+    Ok((value, format!("{}{})", prefix, parts.join(", "))))
+}
+
+/// 中位数，偶数个时取中间两个的平均
+fn median(mut legs: Vec<Leg>) -> Result<(Decimal, String), EvalError> {
+    legs.sort_by_key(|l| l.value);
+    let n = legs.len();
+    let (lo, hi) = if n % 2 == 1 { (n / 2, n / 2 + 1) } else { (n / 2 - 1, n / 2 + 1) };
+    let kept = &legs[lo..hi];
+    let mut sum = Decimal::ZERO;
+    for leg in kept {
+        sum = sum.checked_add(leg.value).ok_or(EvalError::Overflow)?;
+    }
+    let value = sum / Decimal::from(kept.len());
+    let dropped: Vec<&Leg> = legs[..lo].iter().chain(&legs[hi..]).collect();
+    Ok((value, render_kept_dropped("median(", kept.iter(), dropped)))
+}
+
+/// 排序后两端各去掉 floor(n * pct / 100) 个值，剩余的等权平均
+fn trimmed_mean(pct: Decimal, mut legs: Vec<Leg>) -> Result<(Decimal, String), EvalError> {
+    legs.sort_by_key(|l| l.value);
+    let n = legs.len();
+    let trim = (Decimal::from(n) * pct / Decimal::from(100))
+        .floor()
+        .to_usize()
+        .unwrap_or(0);
+    let kept = &legs[trim..n - trim];
+    let mut sum = Decimal::ZERO;
+    for leg in kept {
+        sum = sum.checked_add(leg.value).ok_or(EvalError::Overflow)?;
+    }
+    let value = sum / Decimal::from(kept.len());
+    let dropped: Vec<&Leg> = legs[..trim].iter().chain(&legs[n - trim..]).collect();
+    let prefix = format!("trimmed_mean({}%: ", pct.normalize());
+    Ok((value, render_kept_dropped(&prefix, kept.iter(), dropped)))
+}
+
+/// 最大值与最小值的平均，中间的值不参与计算
+fn mid_range(mut legs: Vec<Leg>) -> Result<(Decimal, String), EvalError> {
+    legs.sort_by_key(|l| l.value);
+    let n = legs.len();
+    let (min, max) = (&legs[0], &legs[n - 1]);
+    let value = min.value.checked_add(max.value).ok_or(EvalError::Overflow)? / Decimal::TWO;
+    let kept: Vec<&Leg> = if n == 1 { vec![min] } else { vec![min, max] };
+    let dropped: Vec<&Leg> = if n > 2 { legs[1..n - 1].iter().collect() } else { Vec::new() };
+    Ok((value, render_kept_dropped("mid_range(", kept.into_iter(), dropped)))
+}
+
+/// 渲染为 "fn(参与计算的值 | dropped: 被丢弃的值)"，便于审计
+fn render_kept_dropped<'a>(
+    prefix: &str,
+    kept: impl Iterator<Item = &'a Leg>,
+    dropped: Vec<&Leg>,
+) -> String {
+    let kept: Vec<&str> = kept.map(|l| l.computed.as_str()).collect();
+    let mut out = format!("{}{}", prefix, kept.join(", "));
+    if !dropped.is_empty() {
+        let dropped: Vec<&str> = dropped.iter().map(|l| l.computed.as_str()).collect();
+        out.push_str(&format!(" | dropped: {}", dropped.join(", ")));
+    }
+    out.push(')');
+    out
+}
+
+/// 左右子表达式不加括号所需的最低优先级；右侧对 '-' 和 '/' 需要更高优先级
+fn child_precedence(op: BinaryOp) -> (u8, u8) {
+    let p = op.precedence();
+    match op {
+        BinaryOp::Add | BinaryOp::Mul => (p, p),
+        BinaryOp::Sub | BinaryOp::Div => (p, p + 1),
+    }
+}
+
+fn wrap(precedence: u8, min_precedence: u8, text: &str) -> String {
+    if precedence < min_precedence {
+        format!("({})", text)
+    } else {
+        text.to_string()
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Source(s) => write!(f, "{}", s),
+            Expr::Index(name) => write!(f, "index({})", name),
+            Expr::Neg(inner) => write!(f, "-{}", wrap(inner.precedence(), NEG, &inner.to_string())),
+            Expr::Binary(op, l, r) => {
+                let (lp, rp) = child_precedence(*op);
+                write!(
+                    f,
+                    "{} {} {}",
+                    wrap(l.precedence(), lp, &l.to_string()),
+                    op.symbol(),
+                    wrap(r.precedence(), rp, &r.to_string())
+                )
+            }
+            Expr::Call(function, args) => {
+                let parts: Vec<String> = args
+                    .iter()
+                    .map(|a| match a.weight {
+                        Some(w) => format!("{}:{}", a.expr, w),
+                        None => a.expr.to_string(),
+                    })
+                    .collect();
+                write!(f, "{}({})", function.name(), parts.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    /// 测试用上下文：固定行情和指数值，按中位数偏离 0.3% 剔除
+    #[derive(Default)]
+    struct TestContext {
+        prices: HashMap<String, Decimal>,
+        volumes: HashMap<String, Decimal>,
+        indices: HashMap<String, Decimal>,
+    }
+
+    impl TestContext {
+        fn price(mut self, key: &str, value: &str) -> Self {
+            self.prices.insert(key.to_string(), dec(value));
+            self
+        }
+
+        fn volume(mut self, key: &str, value: &str) -> Self {
+            self.volumes.insert(key.to_string(), dec(value));
+            self
+        }
+
+        fn index(mut self, name: &str, value: &str) -> Self {
+            self.indices.insert(name.to_string(), dec(value));
+            self
+        }
+    }
+
+    impl EvalContext for TestContext {
+        fn price(&self, source: &SourceRef) -> Option<Quote> {
+            let price = *self.prices.get(&source.key())?;
+            let volume = self.volumes.get(&source.key()).copied();
+            Some(Quote { price, ts: 0, volume })
+        }
+
+        fn index(&self, name: &str) -> Option<Quote> {
+            let price = *self.indices.get(name)?;
+            Some(Quote { price, ts: 0, volume: None })
+        }
+
+        fn reject_outliers(&self, values: &[Decimal]) -> Vec<usize> {
+            if values.len() < 3 {
+                return Vec::new();
+            }
+            let mut sorted = values.to_vec();
+            sorted.sort();
+            let reference = sorted[sorted.len() / 2];
+            values
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| ((**v - reference) / reference).abs() > dec("0.003"))
+                .map(|(i, _)| i)
+                .collect()
+        }
+    }
+
+    fn eval(formula: &str, ctx: &TestContext) -> Evaluated {
+        parse(formula).unwrap().evaluate(ctx).unwrap()
+    }
+
+    fn value(formula: &str) -> Decimal {
+        eval(formula, &TestContext::default()).value
+    }
+
+    /// 四个相互偏离不超过 0.3% 的行情，A.X 和 B.X 带成交额
+    fn market() -> TestContext {
+        TestContext::default()
+            .price("A.X", "100")
+            .price("B.X", "100.2")
+            .price("C.X", "100.1")
+            .price("D.X", "100.3")
+            .volume("A.X", "1")
+            .volume("B.X", "3")
+    }
+
+    fn tokens(input: &str) -> Vec<Token> {
+        tokenize(input).unwrap().into_iter().map(|(t, _)| t).collect()
+    }
+
+    fn source(exchange: &str, symbol: &str, field: PriceField) -> Token {
+        Token::Source(SourceRef {
+            exchange: exchange.to_string(),
+            symbol: symbol.to_string(),
+            field,
+        })
+    }
+
+    #[test]
+    fn tokenizes_sources_fields_and_operators() {
+        assert_eq!(
+            tokens("Okex.BTC-USDT.mid * 2"),
+            vec![source("Okex", "BTC-USDT", PriceField::Mid), Token::Star, Token::Number(dec("2"))]
+        );
+        assert_eq!(
+            tokens("Binance.BTCUSDT-Okex.BTC-USDT"),
+            vec![
+                source("Binance", "BTCUSDT", PriceField::Last),
+                Token::Minus,
+                source("Okex", "BTC-USDT", PriceField::Last),
+            ]
+        );
+        assert_eq!(tokens("Okex.BTC-USD-240927"), vec![source("Okex", "BTC-USD-240927", PriceField::Last)]);
+        assert_eq!(
+            tokens("wavg(index(X):0.5)"),
+            vec![
+                Token::Ident("wavg".to_string()),
+                Token::LParen,
+                Token::Ident("index".to_string()),
+                Token::LParen,
+                Token::Ident("X".to_string()),
+                Token::RParen,
+                Token::Colon,
+                Token::Number(dec("0.5")),
+                Token::RParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_ambiguous_minus_after_symbol() {
+        assert_eq!(
+            parse("Binance.BTCUSDT-1"),
+            Err(FormulaError::AmbiguousMinus {
+                symbol: "BTCUSDT".to_string(),
+                position: 15,
+            })
+        );
+        let ctx = TestContext::default().price("Binance.BTCUSDT", "100");
+        assert_eq!(eval("Binance.BTCUSDT - 1", &ctx).value, dec("99"));
+    }
+
+    #[test]
+    fn respects_precedence_and_associativity() {
+        assert_eq!(value("1 + 2 * 3"), dec("7"));
+        assert_eq!(value("(1 + 2) * 3"), dec("9"));
+        assert_eq!(value("10 - 4 - 3"), dec("3"));
+        assert_eq!(value("8 / 4 / 2"), dec("1"));
+        assert_eq!(parse("1 - (2 - 3)").unwrap().to_string(), "1 - (2 - 3)");
+        assert_eq!(parse("(1 * 2) + 3").unwrap().to_string(), "1 * 2 + 3");
+    }
+
+    #[test]
+    fn evaluates_unary_minus() {
+        assert_eq!(value("-2 * 3"), dec("-6"));
+        assert_eq!(value("--2"), dec("2"));
+        assert_eq!(value("-(1 + 2)"), dec("-3"));
+        assert_eq!(value("2 - -1"), dec("3"));
+    }
+
+    #[test]
+    fn splits_okx_symbols_from_subtraction() {
+        let ctx = TestContext::default()
+            .price("Binance.BTCUSDT", "60000")
+            .price("Okex.BTC-USDT", "60010");
+        let expr = parse("Binance.BTCUSDT-Okex.BTC-USDT").unwrap();
+        let keys: Vec<String> = expr.sources().iter().map(|s| s.key()).collect();
+        assert_eq!(keys, vec!["Binance.BTCUSDT", "Okex.BTC-USDT"]);
+        assert_eq!(expr.to_string(), "Binance.BTCUSDT - Okex.BTC-USDT");
+        assert_eq!(eval("Binance.BTCUSDT-Okex.BTC-USDT", &ctx).value, dec("-10"));
+    }
+
+    #[test]
+    fn renders_computed_formula_with_prices() {
+        let ctx = TestContext::default()
+            .price("Binance.BTCUSDT", "60000")
+            .price("Okex.BTC-USDT", "60010");
+        let v = eval("(Binance.BTCUSDT + Okex.BTC-USDT) / 2", &ctx);
+        assert_eq!(v.value, dec("60005"));
+        assert_eq!(v.computed, "(60000 + 60010) / 2");
+    }
+
+    #[test]
+    fn counts_summed_source_terms() {
+        let terms = |formula: &str| parse(formula).unwrap().summed_source_terms();
+        assert_eq!(terms("(Binance.BTCUSDT + Okex.BTC-USDT) / 2"), 2);
+        assert_eq!(terms("A.X + B.X + C.X"), 3);
+        assert_eq!(terms("0.5 * (A.X + B.X)"), 2);
+        assert_eq!(terms("avg(A.X, B.X)"), 1);
+        assert_eq!(terms("A.X - B.X"), 1);
+        assert_eq!(terms("A.X * B.X"), 1);
+        assert_eq!(terms("A.X + 1"), 1);
+        assert_eq!(terms("index(BTCUSDT) + index(ETHUSDT)"), 0);
+    }
+
+    #[test]
+    fn aggregate_overflow_is_an_error() {
+        let max = Decimal::MAX.to_string();
+        let ctx = TestContext::default().price("A.X", &max).price("B.X", &max);
+        for formula in ["median(A.X, B.X)", "mid_range(A.X, B.X)", "avg(A.X, B.X)"] {
+            assert_eq!(parse(formula).unwrap().evaluate(&ctx).err(), Some(EvalError::Overflow), "{}", formula);
+        }
+    }
+
+    #[test]
+    fn evaluates_each_aggregate() {
+        let ctx = market();
+        assert_eq!(eval("avg(A.X, B.X, C.X)", &ctx).value, dec("100.1"));
+        assert_eq!(eval("wavg(A.X:1, B.X:3)", &ctx).value, dec("100.15"));
+        assert_eq!(eval("median(A.X, B.X, C.X, D.X)", &ctx).value, dec("100.15"));
+        assert_eq!(eval("trimmed_mean(25, A.X, B.X, C.X, D.X)", &ctx).value, dec("100.15"));
+        assert_eq!(eval("mid_range(A.X, C.X, D.X)", &ctx).value, dec("100.15"));
+
+        let vwap = eval("vwap(A.X, B.X, C.X)", &ctx);
+        assert_eq!(vwap.value, dec("100.15"));
+        assert!(vwap.computed.ends_with("| no volume: C.X)"));
+
+        let first = eval("first(Z.X, B.X)", &ctx);
+        assert_eq!(first.value, dec("100.2"));
+        assert_eq!(first.missing, vec!["Z.X".to_string()]);
+    }
+
+    #[test]
+    fn aggregates_skip_missing_sources() {
+        let v = eval("avg(A.X, Z.X)", &market());
+        assert_eq!(v.value, dec("100"));
+        assert_eq!(v.sources, 1);
+        assert_eq!(v.missing, vec!["Z.X".to_string()]);
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_eq!(parse("avg("), Err(FormulaError::UnexpectedEnd));
+        assert!(matches!(parse("1 $ 2"), Err(FormulaError::UnexpectedChar { ch: '$', position: 2 })));
+        assert!(matches!(parse("1 2"), Err(FormulaError::UnexpectedToken { position: 2, .. })));
+        assert!(matches!(parse("foo(1)"), Err(FormulaError::UnknownFunction { .. })));
+        assert!(matches!(parse("wavg(A.X, B.X:1)"), Err(FormulaError::InvalidArguments { .. })));
+        assert!(matches!(parse("avg(A.X:1)"), Err(FormulaError::InvalidArguments { .. })));
+        assert!(matches!(parse("trimmed_mean(50, A.X)"), Err(FormulaError::InvalidArguments { .. })));
+    }
+
+    #[test]
+    fn reports_eval_errors() {
+        let ctx = market();
+        let err = |formula: &str| parse(formula).unwrap().evaluate(&ctx).unwrap_err();
+        assert_eq!(err("1 / 0"), EvalError::DivisionByZero);
+        assert_eq!(err("Z.X"), EvalError::MissingPrice("Z.X".to_string()));
+        assert_eq!(err("index(Z)"), EvalError::MissingIndex("Z".to_string()));
+        assert_eq!(err("avg(Z.X, Y.X)"), EvalError::NoLiveSources("avg"));
+    }
+
+    #[test]
+    fn index_refs_are_not_rejected_as_outliers() {
+        let ctx = TestContext::default()
+            .index("BTCUSDT", "60000")
+            .index("ETHUSDT", "3000")
+            .index("SOLUSDT", "150");
+        let v = eval("wavg(index(BTCUSDT):0.5, index(ETHUSDT):0.3, index(SOLUSDT):0.2)", &ctx);
+        assert_eq!(v.value, dec("30930"));
+        assert!(v.excluded.is_empty());
+    }
+
+    #[test]
+    fn exchange_sources_are_rejected_as_outliers() {
+        let ctx = TestContext::default()
+            .price("Binance.BTCUSDT", "60000")
+            .price("Okex.BTC-USDT", "60010")
+            .price("Bitget.BTCUSDT", "61000")
+            .index("ETHUSDT", "3000");
+        let v = eval("avg(Binance.BTCUSDT, Okex.BTC-USDT, Bitget.BTCUSDT, index(ETHUSDT))", &ctx);
+        assert_eq!(v.excluded, vec!["Bitget.BTCUSDT".to_string()]);
+        assert_eq!(v.value, dec("41003.333333333333333333333333"));
+    }
+
+    #[test]
+    fn converted_legs_are_rejected_as_outliers() {
+        let ctx = TestContext::default()
+            .price("Binance.BTCUSDT", "60000")
+            .price("Okex.BTC-USDT", "60010")
+            .price("Kraken.XBTEUR", "60000")
+            .index("EURUSDT", "1.1");
+        let v = eval("avg(Binance.BTCUSDT, Okex.BTC-USDT, Kraken.XBTEUR * index(EURUSDT))", &ctx);
+        assert_eq!(v.excluded, vec!["Kraken.XBTEUR * index(EURUSDT)".to_string()]);
+        assert_eq!(v.value, dec("60005"));
+    }
+}
@@ -1,6 +1,10 @@
+use crate::core::index::circuit_breaker::CircuitBreaker;
+use crate::core::index::formula::{self, EvalContext, EvalError, Expr, PriceField, Quote, SourceRef};
+use crate::core::index::smoothed_series::time_weighted_avg;
+use crate::core::model::{self, SettlementConfig};
 use rust_decimal::prelude::*;
-use std::collections::HashMap;
-use tracing::warn;
+use std::collections::{HashMap, VecDeque};
+use tracing::{debug, warn};
 
 /// 指数配置
 #[derive(Debug, Clone)]
@@ -9,6 +13,54 @@ pub struct IndexConfig {
     pub formula: String,
 }
 
+/// 单个行情的最新价格及其时间
+#[derive(Debug, Clone, Copy)]
+pub struct PriceEntry {
+    /// 最新成交价
+    pub price: Option<Decimal>,
+    /// 交易所行情时间（毫秒），未知时为 0
+    pub ts: i64,
+    /// 最优买价
+    pub bid: Option<Decimal>,
+    /// 最优卖价
+    pub ask: Option<Decimal>,
+    /// 最优买卖价的时间（毫秒），未知时为 0
+    pub book_ts: i64,
+    /// 本地收到行情的时间（毫秒）
+    pub received_at: i64,
+    /// 24h 计价币成交额，未知时为 None
+    pub volume: Option<Decimal>,
+}
+
+impl PriceEntry {
+    /// 交易所时间和本地接收时间中较旧的一个，用于判断是否过期
+    pub fn effective_ts(&self) -> i64 {
+        self.effective(self.ts)
+    }
+
+    /// 取指定字段的价格及其有效时间，mid 需要买卖价都存在
+    pub fn field(&self, field: PriceField) -> Option<(Decimal, i64)> {
+        let book_ts = self.effective(self.book_ts);
+        match field {
+            PriceField::Last => self.price.map(|p| (p, self.effective_ts())),
+            PriceField::Bid => self.bid.map(|p| (p, book_ts)),
+            PriceField::Ask => self.ask.map(|p| (p, book_ts)),
+            PriceField::Mid => match (self.bid, self.ask) {
+                (Some(bid), Some(ask)) => Some(((bid + ask) / Decimal::TWO, book_ts)),
+                _ => None,
+            },
+        }
+    }
+
+    fn effective(&self, ts: i64) -> i64 {
+        if ts > 0 {
+            ts.min(self.received_at)
+        } else {
+            self.received_at
+        }
+    }
+}
+
 /// 计算结果
 #[derive(Debug, Clone)]
 pub struct Index {
@@ -17,6 +69,30 @@ pub struct Index {
     pub last: Decimal,
     pub formula: String,
     pub computed_formula: String, // 增加字段，用于展示实际计算
+    /// 本次因偏离参考价过大被剔除的行情
+    pub excluded_sources: Vec<String>,
+    /// 本次因超过 max_age_ms 被剔除的行情
+    pub stale_sources: Vec<String>,
+    /// 参与计算的有效行情数
+    pub source_count: usize,
+    /// 参与计算的行情中最旧的时间（毫秒）
+    pub source_ts: Option<i64>,
+    /// 实际参与计算的行情
+    pub used_sources: Vec<String>,
+    /// 参与计算的行情 24h 成交额合计（计价币），用于其他指数 vwap 引用
+    pub volume: Option<Decimal>,
+    /// 计价币换算前的值，未换算时为 None
+    pub raw_last: Option<Decimal>,
+    /// 计价币换算使用的参考指数值
+    pub conversion_rate: Option<Decimal>,
+}
+
+/// 已发布的指数采样，用于结算价计算
+#[derive(Debug, Clone)]
+pub struct IndexSample {
+    pub ts: i64,
+    pub last: Decimal,
+    pub sources: Vec<String>,
 }
 
 /// 核心计算器
@@ -24,85 +100,186 @@ pub struct IndexCalculator {
     pub index_name: String,
     /// 异常比例，类似 Java 的 EXCEPTION_PERCENT_MARGIN
     pub exception_percent_margin: Decimal,
+    /// 行情最大延迟（毫秒），0 表示不检查
+    pub max_age_ms: i64,
     /// 当前各交易所最新价格
-    pub price_map: HashMap<String, Decimal>, // key: "Binance.BTCUSDT"
-
-    pub index_list : Vec<Index>,
+    pub price_map: HashMap<String, PriceEntry>, // key: "Binance.BTCUSDT"
+    /// EDP 时间窗口（毫秒），0 表示不计算
+    pub edp_window_ms: i64,
+    /// EDP 窗口内的指数采样 (时间毫秒, 指数值)
+    pub index_list: VecDeque<(i64, Decimal)>,
+    /// 结算采样保留时长（毫秒），0 表示不保留
+    pub sample_retention_ms: i64,
+    /// 最近 sample_retention_ms 内的指数采样（不含降级时保持的值）
+    pub samples: VecDeque<IndexSample>,
+    /// 指数熔断器
+    pub breaker: CircuitBreaker,
+    /// 发布值保留的小数位数
+    pub scale: u32,
+    /// 小数位截取方式
+    pub rounding: RoundingStrategy,
+    /// 当前公式
+    pub formula: String,
+    /// 当前公式的语法树，加载或切换公式时解析一次，解析失败时为 None
+    expr: Option<Expr>,
 }
 
 impl IndexCalculator {
-    pub fn new(index_name: String, exception_percent_margin: Decimal) -> Self {
+    pub fn new(
+        index_name: String,
+        exception_percent_margin: Decimal,
+        max_age_ms: i64,
+        edp_window_ms: i64,
+    ) -> Self {
         Self {
             index_name,
             exception_percent_margin,
+            max_age_ms,
             price_map: HashMap::new(),
-            index_list: Vec::new(),
+            edp_window_ms,
+            index_list: VecDeque::new(),
+            sample_retention_ms: 0,
+            samples: VecDeque::new(),
+            breaker: CircuitBreaker::default(),
+            scale: 18,
+            rounding: RoundingStrategy::MidpointAwayFromZero,
+            formula: String::new(),
+            expr: None,
+        }
+    }
+
+    /// 设置公式并解析，与当前公式相同时不重复解析
+    pub fn set_formula(&mut self, formula: &str) {
+        if self.expr.is_some() && self.formula == formula {
+            return;
         }
+        self.formula = formula.to_string();
+        self.expr = match formula::parse(formula) {
+            Ok(expr) => {
+                // 旧的手写平均公式，如 (Binance.BTCUSDT + Bitget.BTCUSDT)/2，单个行情缺失不再被平均掉
+                let terms = expr.summed_source_terms();
+                if terms > 1 {
+                    warn!(
+                        "name {} formula {} adds {} sources outside an aggregate function, a single missing source fails the whole index; use avg(...) instead",
+                        self.index_name, formula, terms
+                    );
+                }
+                Some(expr)
+            }
+            Err(e) => {
+                warn!("name {} invalid formula {}: {}", self.index_name, formula, e);
+                None
+            }
+        };
+    }
+
+    /// 按 index_config 创建计算器，结算采样保留该指数所有结算配置中最长的窗口
+    pub fn from_config(config: &model::IndexConfig, settlement_configs: &[SettlementConfig]) -> Self {
+        let mut calculator = Self::new(
+            config.name.clone(),
+            config.outlier_margin,
+            config.max_age_ms,
+            config.edp_window_secs as i64 * 1000,
+        );
+        calculator.sample_retention_ms = settlement_configs
+            .iter()
+            .filter(|s| s.index_name == config.name)
+            .map(|s| s.window_minutes as i64 * 60_000)
+            .max()
+            .unwrap_or(0);
+        calculator.breaker = CircuitBreaker::new(
+            config.breaker_max_tick_move,
+            config.breaker_max_minute_move,
+            config.breaker_confirm_ticks.max(0) as u32,
+        );
+        calculator.scale = config.scale.clamp(0, 18) as u32;
+        calculator.rounding = config.rounding_strategy.strategy();
+        calculator.set_formula(&config.formula);
+        calculator
+    }
+
+    /// 按 scale 和 rounding 截取小数位，所有对外发布的值都经过这里
+    pub fn round(&self, value: Decimal) -> Decimal {
+        value.round_dp_with_strategy(self.scale, self.rounding)
     }
 
     /// 更新价格（可由 WebSocket 客户端定期写入）
-    pub fn update_price(&mut self, key: &str, price: Decimal) {
+    pub fn update_price(&mut self, key: &str, price: PriceEntry) {
         self.price_map.insert(key.to_string(), price);
     }
 
-    pub fn calculate_index(&self, name: &str, formula: &str, time: u64) -> Option<Index> {
-        if self.price_map.is_empty() {
+    /// 按当前公式计算指数，indices 为本轮已计算出的其他指数（用于 index(NAME) 引用）；返回的 last 未截取小数位。
+    /// 公式无效时返回 None，错误已在 set_formula 时记录
+    pub fn calculate_index(
+        &self,
+        name: &str,
+        time: u64,
+        indices: &HashMap<String, Quote>,
+    ) -> Option<Index> {
+        if self.price_map.is_empty() && indices.is_empty() {
             warn!("name {} Price map is empty", name);
             return None;
         }
 
-        let formula_clean = formula.replace(" ", "").replace("(", "").replace(")", "");
-        let parts: Vec<&str> = formula_clean.split('/').collect();
-        if parts.is_empty() {
-            return None;
-        }
+        let expr = self.expr.as_ref()?;
+        let formula = self.formula.as_str();
 
-        // numerator
-        let numerator = parts[0];
-        let tickers: Vec<&str> = numerator.split('+').collect();
-        let mut sum = Decimal::ZERO;
-        let mut valid_count = 0u32; // 实际存在价格的个数
-        let mut computed_parts = Vec::new();
-
-        for t in tickers {
-            if let Some(price) = self.price_map.get(t) {
-                sum += *price;
-                valid_count += 1;
-                computed_parts.push(price.to_string());
-            } else {
-                warn!("Price not found for {}", t);
+        let ctx = TickContext {
+            calculator: self,
+            indices,
+            now_ms: chrono::Utc::now().timestamp_millis(),
+        };
+        let evaluated = match expr.evaluate(&ctx) {
+            Ok(evaluated) => evaluated,
+            Err(EvalError::MissingPrice(key)) => {
+                warn!("Price not found for {}", key);
+                return None;
             }
-        }
+            Err(EvalError::StalePrice(key)) => {
+                warn!("name {} price is stale for {}", name, key);
+                return None;
+            }
+            Err(EvalError::MissingIndex(index)) => {
+                warn!("name {} index value not found for {}", name, index);
+                return None;
+            }
+            Err(e) => {
+                warn!("name {} evaluate formula {} failed: {}", name, formula, e);
+                return None;
+            }
+        };
 
-        if valid_count == 0 {
-            return None; // 所有价格都缺失，无法计算
+        for key in &evaluated.missing {
+            warn!("Price not found for {}", key);
         }
 
-        // 用实际存在价格的数量平均
-        let mut last = sum / Decimal::from(valid_count);
-
-        // // 如果公式里有除法，继续除
-        // if parts.len() > 1 {
-        //     if let Ok(denom) = parts[1].parse::<Decimal>() {
-        //         last /= denom;
-        //     }
-        // }
-
-        // 构建 computed_formula：使用有效数据的个数来除，而不是公式里原来的除数
-        let computed_formula = if valid_count > 1 {
-            format!("({}) / {}", computed_parts.join(" + "), valid_count)
-        } else {
-            computed_parts.join(" + ")
-        };
-
-        last = self.check_exception(last);
+        if !evaluated.stale.is_empty() {
+            warn!(
+                "name {} stale sources {:?}, max_age_ms {}",
+                name, evaluated.stale, self.max_age_ms
+            );
+        }
+        if !evaluated.excluded.is_empty() {
+            warn!(
+                "name {} excluded sources {:?}, margin {}",
+                name, evaluated.excluded, self.exception_percent_margin
+            );
+        }
 
         let index = Index {
             id: time,
             symbol: name.to_string(),
-            last,
+            last: evaluated.value,
             formula: formula.to_string(),
-            computed_formula,
+            computed_formula: evaluated.computed,
+            excluded_sources: evaluated.excluded,
+            stale_sources: evaluated.stale,
+            source_count: evaluated.sources,
+            source_ts: evaluated.ts,
+            used_sources: evaluated.used,
+            volume: evaluated.volume,
+            raw_last: None,
+            conversion_rate: None,
         };
 
         // self.index_list.push(index.clone());
@@ -110,44 +287,145 @@ impl IndexCalculator {
         Some(index)
     }
 
-    // pub fn calculate_edp(&mut self) -> Option<Decimal> {
-    //     if self.index_list.is_empty() {
-    //         return None;
-    //     }
-    //
-    //     // 当前时间 - 10分钟
-    //     let cutoff = Utc::now() - Duration::minutes(10);
-    //     let cutoff_ms = cutoff.timestamp_millis();
-    //
-    //     // 删除旧数据 + 累加最新价格
-    //     let mut sum = Decimal::ZERO;
-    //
-    //     // 过滤保留有效数据
-    //     self.index_list.retain(|idx| idx.id >= cutoff_ms as u64);
-    //
-    //     for idx in &self.index_list {
-    //         sum += idx.last;
-    //     }
-    //
-    //     // 如果全被清理掉了
-    //     if self.index_list.is_empty() {
-    //         return None;
-    //     }
-    //
-    //     // 求平均
-    //     let avg = sum / Decimal::from(self.index_list.len() as u64);
-    //
-    //     info!(
-    //         "Calculate EDP [{}] symbol [{}] size [{}]",
-    //         avg, self.index_name, self.index_list.len()
-    //     );
-    //
-    //     Some(avg.round_dp_with_strategy(8, rust_decimal::RoundingStrategy::MidpointAwayFromZero))
-    // }
-
-
-    fn check_exception(&self, price: Decimal) -> Decimal {
-        // 简单示例：可根据 median 或其他规则实现
-        price // 这里暂时不处理异常
+    /// 计算 EDP：最近 edp_window_ms 内指数的时间加权平均（TWAP）。
+    /// 每个采样值的权重为它持续的时间，窗口开始前的最后一个采样从窗口起点开始计算。
+    /// last 为 None 表示本次是降级或熔断保持的值，不加入采样，与结算价使用的采样一致
+    pub fn calculate_edp(&mut self, now_ms: i64, last: Option<Decimal>) -> Option<Decimal> {
+        if self.edp_window_ms <= 0 {
+            return None;
+        }
+        if let Some(last) = last {
+            self.index_list.push_back((now_ms, last));
+        }
+
+        // 删除旧数据，保留窗口开始前的最后一个采样
+        let cutoff = now_ms - self.edp_window_ms;
+        while self.index_list.len() > 1 && self.index_list[1].0 <= cutoff {
+            self.index_list.pop_front();
+        }
+
+        let latest = self.index_list.back()?.1;
+        let edp = time_weighted_avg(self.index_list.iter().copied(), cutoff, now_ms)
+            .map_or(latest, |avg| self.round(avg));
+
+        debug!(
+            "Calculate EDP [{}] symbol [{}] size [{}]",
+            edp, self.index_name, self.index_list.len()
+        );
+
+        Some(edp)
+    }
+
+    /// 记录一次指数采样，供结算时取窗口的时间加权平均；与 EDP 一样保留保留期开始前的最后一个采样
+    pub fn record_sample(&mut self, now_ms: i64, last: Decimal, sources: &[String]) {
+        if self.sample_retention_ms <= 0 {
+            return;
+        }
+        self.samples.push_back(IndexSample {
+            ts: now_ms,
+            last,
+            sources: sources.to_vec(),
+        });
+        let cutoff = now_ms - self.sample_retention_ms;
+        while self.samples.len() > 1 && self.samples[1].ts <= cutoff {
+            self.samples.pop_front();
+        }
+    }
+
+    /// (from_ms, to_ms] 内指数的时间加权平均，以及窗口开始前的最后一个采样和窗口内的采样
+    pub fn twap_between(&self, from_ms: i64, to_ms: i64) -> Option<(Decimal, Vec<IndexSample>)> {
+        let twap = time_weighted_avg(self.samples.iter().map(|s| (s.ts, s.last)), from_ms, to_ms)?;
+        let first = self.samples.iter().rposition(|s| s.ts <= from_ms).unwrap_or(0);
+        let used = self
+            .samples
+            .iter()
+            .skip(first)
+            .take_while(|s| s.ts <= to_ms)
+            .cloned()
+            .collect();
+        Some((twap, used))
+    }
+
+    /// 异常值检测：以各行情的中位数为参考价，偏离超过 exception_percent_margin 的行情被剔除。
+    /// 少于 3 个行情时无法判断哪一个异常，不做剔除。返回需要剔除的下标
+    fn check_exception(&self, prices: &[Decimal]) -> Vec<usize> {
+        if prices.len() < 3 || self.exception_percent_margin <= Decimal::ZERO {
+            return Vec::new();
+        }
+
+        let mut sorted = prices.to_vec();
+        sorted.sort();
+        let n = sorted.len();
+        let reference = if n % 2 == 1 {
+            sorted[n / 2]
+        } else {
+            (sorted[n / 2 - 1] + sorted[n / 2]) / Decimal::TWO
+        };
+        if reference.is_zero() {
+            return Vec::new();
+        }
+
+        prices
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| ((**p - reference) / reference).abs() > self.exception_percent_margin)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// 单次计算的求值上下文，固定本次计算的当前时间
+struct TickContext<'a> {
+    calculator: &'a IndexCalculator,
+    indices: &'a HashMap<String, Quote>,
+    now_ms: i64,
+}
+
+impl EvalContext for TickContext<'_> {
+    fn price(&self, source: &SourceRef) -> Option<Quote> {
+        let entry = self.calculator.price_map.get(&source.key())?;
+        let (price, ts) = entry.field(source.field)?;
+        Some(Quote {
+            price,
+            ts,
+            volume: entry.volume,
+        })
+    }
+
+    fn index(&self, name: &str) -> Option<Quote> {
+        self.indices.get(name).copied()
+    }
+
+    /// 交易所时间和本地接收时间任一超过最大延迟都视为过期；
+    /// 换算得到的合成行情取其输入中最旧的时间
+    fn is_stale(&self, ts: i64) -> bool {
+        let max_age_ms = self.calculator.max_age_ms;
+        max_age_ms > 0 && self.now_ms - ts > max_age_ms
+    }
+
+    fn reject_outliers(&self, values: &[Decimal]) -> Vec<usize> {
+        self.calculator.check_exception(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn edp_skips_held_values() {
+        let mut calc = IndexCalculator::new("BTCUSDT".to_string(), Decimal::ZERO, 0, 10_000);
+        assert_eq!(calc.calculate_edp(0, None), None);
+        assert_eq!(calc.calculate_edp(0, Some(dec("100"))), Some(dec("100")));
+        // 保持期间不加入采样，100 一直持续到下一个有效值
+        assert_eq!(calc.calculate_edp(5000, None), Some(dec("100")));
+        assert_eq!(calc.calculate_edp(10_000, Some(dec("200"))), Some(dec("100")));
+        assert_eq!(calc.calculate_edp(15_000, None), Some(dec("150")));
+        assert_eq!(calc.index_list.len(), 2);
     }
 }
@@ -0,0 +1,130 @@
+use crate::core::index::index_calculator::IndexCalculator;
+use crate::core::index::smoothed_series::{ema_step, time_weighted_avg};
+use rust_decimal::prelude::*;
+use std::collections::VecDeque;
+
+/// 单次标记价格计算结果
+#[derive(Debug, Clone)]
+pub struct MarkPrice {
+    /// 现货指数
+    pub index_price: Decimal,
+    /// 永续合约价格，行情不可用时为 None
+    pub perp_price: Option<Decimal>,
+    /// 溢价指数 (perp - index) / index，永续行情不可用时为 None
+    pub premium_index: Option<Decimal>,
+    /// 最近 premium_window_ms 内溢价指数的时间加权平均
+    pub premium_avg: Decimal,
+    /// 溢价指数的 EMA
+    pub premium_ema: Decimal,
+    /// 标记价格 index * (1 + clamp(premium_ema))
+    pub mark_price: Decimal,
+}
+
+/// 标记价格计算器：用永续合约价格相对现货指数的溢价计算 EMA，mark = index * (1 + clamp(EMA))。
+/// 永续行情不可用时保持上一次的 EMA
+pub struct MarkPriceCalculator {
+    pub name: String,
+    /// 引用的现货指数
+    pub index_name: String,
+    /// 永续合约价格公式，如 "avg(Okex.BTCUSDT_PERP, Bitget.BTCUSDT_PERP)"
+    pub perp_formula: String,
+    /// 永续合约行情，复用指数计算器的过期检查和异常剔除
+    pub perp: IndexCalculator,
+    /// EMA 时间窗口（毫秒），alpha = min(1, dt / ema_window_ms)
+    pub ema_window_ms: i64,
+    /// 溢价平均窗口（毫秒）
+    pub premium_window_ms: i64,
+    /// 溢价采样保留时长（毫秒），不小于 premium_window_ms，资金费率取整个资金费周期的平均
+    pub retention_ms: i64,
+    /// EMA 溢价的上下限（如 0.005 表示 ±0.5%），0 表示不限制
+    pub max_premium_rate: Decimal,
+    premium_ema: Option<Decimal>,
+    last_ts: i64,
+    /// 溢价指数采样 (时间毫秒, 溢价)
+    premiums: VecDeque<(i64, Decimal)>,
+}
+
+impl MarkPriceCalculator {
+    pub fn new(
+        name: String,
+        index_name: String,
+        perp_formula: String,
+        mut perp: IndexCalculator,
+        ema_window_ms: i64,
+        premium_window_ms: i64,
+        max_premium_rate: Decimal,
+    ) -> Self {
+        perp.set_formula(&perp_formula);
+        Self {
+            name,
+            index_name,
+            perp_formula,
+            perp,
+            ema_window_ms,
+            premium_window_ms,
+            retention_ms: premium_window_ms,
+            max_premium_rate,
+            premium_ema: None,
+            last_ts: 0,
+            premiums: VecDeque::new(),
+        }
+    }
+
+    /// 用最新的现货指数和永续价格更新溢价，返回标记价格；还没有任何溢价时返回 None
+    pub fn update(&mut self, now_ms: i64, index_price: Decimal, perp_price: Option<Decimal>) -> Option<MarkPrice> {
+        if index_price <= Decimal::ZERO {
+            return None;
+        }
+
+        let premium_index = perp_price.map(|perp| (perp - index_price) / index_price);
+        if let Some(premium) = premium_index {
+            let ema = match self.premium_ema {
+                Some(prev) => ema_step(prev, premium, now_ms - self.last_ts, self.ema_window_ms),
+                None => premium,
+            };
+            self.premium_ema = Some(ema);
+            self.last_ts = now_ms;
+
+            self.premiums.push_back((now_ms, premium));
+            let cutoff = now_ms - self.retention_ms.max(self.premium_window_ms);
+            while self.premiums.len() > 1 && self.premiums[1].0 <= cutoff {
+                self.premiums.pop_front();
+            }
+        }
+
+        let premium_ema = self.premium_ema?;
+        let clamped = if self.max_premium_rate > Decimal::ZERO {
+            premium_ema.clamp(-self.max_premium_rate, self.max_premium_rate)
+        } else {
+            premium_ema
+        };
+
+        Some(MarkPrice {
+            index_price,
+            perp_price,
+            premium_index,
+            premium_avg: self.premium_avg(now_ms),
+            premium_ema,
+            mark_price: index_price * (Decimal::ONE + clamped),
+        })
+    }
+
+    /// 最近 premium_window_ms 内溢价指数的时间加权平均
+    fn premium_avg(&self, now_ms: i64) -> Decimal {
+        self.premium_between(now_ms - self.premium_window_ms, now_ms)
+            .map(|(avg, _)| avg)
+            .or_else(|| self.premiums.back().map(|(_, v)| *v))
+            .unwrap_or(Decimal::ZERO)
+    }
+
+    /// (from_ms, to_ms] 内溢价指数的时间加权平均及窗口内的采样数
+    pub fn premium_between(&self, from_ms: i64, to_ms: i64) -> Option<(Decimal, usize)> {
+        let avg = time_weighted_avg(self.premiums.iter().copied(), from_ms, to_ms)?;
+        let count = self
+            .premiums
+            .iter()
+            .filter(|(ts, _)| *ts > from_ms && *ts <= to_ms)
+            .count();
+        Some((avg, count))
+    }
+}
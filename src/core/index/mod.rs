@@ -1,2 +1,7 @@
 pub mod index_calculator;
-pub mod calculator_manager;
\ No newline at end of file
+pub mod calculator_manager;
+pub mod formula;
+pub mod circuit_breaker;
+pub mod mark_price_calculator;
+pub mod smoothed_series;
+pub mod basket_calculator;
\ No newline at end of file
@@ -0,0 +1,170 @@
+use crate::core::model::{DerivedSeries, SmoothingKind};
+use rust_decimal::Decimal;
+use std::collections::VecDeque;
+
+/// 派生平滑序列的增量计算状态，每次发布原始指数时更新一次
+#[derive(Debug, Clone)]
+pub struct SmoothedSeries {
+    /// 发布名称，如 BTCUSDT_EMA_30S
+    pub name: String,
+    pub series: DerivedSeries,
+    /// 最近一次的平滑值，原始指数降级或熔断时保持该值
+    pub last: Option<Decimal>,
+    ema: Option<Decimal>,
+    last_ts: i64,
+    /// SMA 窗口内的发布值 (时间毫秒, 指数值)，保留窗口开始前的最后一个
+    samples: VecDeque<(i64, Decimal)>,
+}
+
+impl SmoothedSeries {
+    pub fn new(index_name: &str, series: DerivedSeries) -> Self {
+        Self {
+            name: series.index_name(index_name),
+            series,
+            last: None,
+            ema: None,
+            last_ts: 0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// 加入一个新的原始指数值，返回平滑后的值
+    pub fn update(&mut self, now_ms: i64, value: Decimal) -> Decimal {
+        let window_ms = self.series.window_secs * 1000;
+        let value = match self.series.kind {
+            SmoothingKind::Ema => {
+                let ema = match self.ema {
+                    Some(prev) => ema_step(prev, value, now_ms - self.last_ts, window_ms),
+                    None => value,
+                };
+                self.ema = Some(ema);
+                self.last_ts = now_ms;
+                ema
+            }
+            SmoothingKind::Sma => {
+                self.samples.push_back((now_ms, value));
+                let cutoff = now_ms - window_ms;
+                while self.samples.len() > 1 && self.samples[1].0 <= cutoff {
+                    self.samples.pop_front();
+                }
+                time_weighted_avg(self.samples.iter().copied(), cutoff, now_ms).unwrap_or(value)
+            }
+        };
+        self.last = Some(value);
+        value
+    }
+
+    /// 持久化时记录的公式，如 EMA(30s) of index(BTCUSDT)
+    pub fn formula(&self, index_name: &str) -> String {
+        format!("{} of index({})", self.series, index_name)
+    }
+}
+
+/// 按时间间隔衰减的 EMA：alpha = min(1, dt / window)，window_ms <= 0 时直接取新值。标记价格的溢价 EMA 和 EMA 序列共用
+pub fn ema_step(prev: Decimal, value: Decimal, dt_ms: i64, window_ms: i64) -> Decimal {
+    if window_ms <= 0 {
+        return value;
+    }
+    let alpha = (Decimal::from(dt_ms.max(0)) / Decimal::from(window_ms)).min(Decimal::ONE);
+    prev + alpha * (value - prev)
+}
+
+/// (from_ms, to_ms] 内的时间加权平均，samples 为按时间升序的 (时间毫秒, 值)。
+/// 每个值持续到下一个采样，窗口开始前的最后一个采样从 from_ms 开始计算；窗口内没有持续时间时返回 None。
+/// EDP、结算价、溢价平均和 SMA 共用
+pub fn time_weighted_avg(
+    samples: impl IntoIterator<Item = (i64, Decimal)>,
+    from_ms: i64,
+    to_ms: i64,
+) -> Option<Decimal> {
+    let mut weighted_sum = Decimal::ZERO;
+    let mut total_ms = 0i64;
+    let mut samples = samples.into_iter().peekable();
+    while let Some((ts, value)) = samples.next() {
+        if ts > to_ms {
+            break;
+        }
+        let start = ts.max(from_ms);
+        let end = samples.peek().map_or(to_ms, |next| next.0.min(to_ms));
+        let duration = end - start;
+        if duration > 0 {
+            weighted_sum += value * Decimal::from(duration);
+            total_ms += duration;
+        }
+    }
+    (total_ms > 0).then(|| weighted_sum / Decimal::from(total_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn series(kind: SmoothingKind, window_secs: i64) -> SmoothedSeries {
+        SmoothedSeries::new("BTCUSDT", DerivedSeries { kind, window_secs })
+    }
+
+    #[test]
+    fn ema_step_moves_by_elapsed_share_of_window() {
+        assert_eq!(ema_step(dec("100"), dec("110"), 3000, 10_000), dec("103"));
+        assert_eq!(ema_step(dec("100"), dec("110"), 20_000, 10_000), dec("110"));
+        assert_eq!(ema_step(dec("100"), dec("110"), 0, 10_000), dec("100"));
+        assert_eq!(ema_step(dec("100"), dec("110"), 3000, 0), dec("110"));
+    }
+
+    #[test]
+    fn ema_converges_to_a_constant_input() {
+        let mut ema = series(SmoothingKind::Ema, 10);
+        assert_eq!(ema.update(0, dec("100")), dec("100"));
+        let mut prev_gap = dec("10");
+        for i in 1..=20 {
+            let value = ema.update(i * 1000, dec("110"));
+            let gap = dec("110") - value;
+            assert!(gap < prev_gap && gap > Decimal::ZERO);
+            prev_gap = gap;
+        }
+        // 每秒剩余差距乘以 0.9，20 秒后约为 10 * 0.9^20
+        assert!(prev_gap < dec("1.3"));
+        assert_eq!(ema.update(40_000, dec("110")), dec("110"));
+    }
+
+    #[test]
+    fn sma_evicts_samples_older_than_the_window() {
+        let mut sma = series(SmoothingKind::Sma, 10);
+        sma.update(0, dec("1000"));
+        sma.update(5000, dec("100"));
+        // 1000 持续 [0, 5000)，100 持续 [5000, 10000)
+        assert_eq!(sma.update(10_000, dec("100")), dec("550"));
+        // 窗口 [5000, 15000] 内只有 100，1000 已移出
+        assert_eq!(sma.update(15_000, dec("200")), dec("100"));
+        assert_eq!(sma.samples.len(), 3);
+        assert_eq!(sma.update(20_000, dec("200")), dec("150"));
+        assert_eq!(sma.samples.len(), 3);
+        assert_eq!(sma.update(30_000, dec("300")), dec("200"));
+        assert_eq!(sma.samples.len(), 2);
+    }
+
+    #[test]
+    fn time_weighted_avg_carries_the_sample_before_the_window() {
+        // 100 持续 [0, 3000)，窗口从 1000 开始只计 2000 毫秒；110 持续 [3000, 5000]
+        let samples = [(-5000, dec("90")), (0, dec("100")), (3000, dec("110"))];
+        assert_eq!(time_weighted_avg(samples, 1000, 5000), Some(dec("105")));
+    }
+
+    #[test]
+    fn time_weighted_avg_ignores_samples_after_the_window() {
+        let samples = [(0, dec("100")), (2000, dec("110")), (5000, dec("200"))];
+        assert_eq!(time_weighted_avg(samples, 0, 4000), Some(dec("105")));
+    }
+
+    #[test]
+    fn time_weighted_avg_without_duration_is_none() {
+        assert_eq!(time_weighted_avg([], 0, 1000), None);
+        assert_eq!(time_weighted_avg([(1000, dec("100"))], 0, 1000), None);
+        assert_eq!(time_weighted_avg([(2000, dec("100"))], 0, 1000), None);
+    }
+}
@@ -14,11 +14,52 @@ pub struct Exchange {
     pub name: String,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct TickerData {
     pub last_pr: String,
     pub ts: String,
     pub inst_id: String,
+    /// 本地收到行情的时间（毫秒）
+    pub received_at: i64,
+    /// 24h 基础币成交量，交易所未提供时为空
+    pub base_volume: String,
+    /// 24h 计价币成交额，交易所未提供时为空
+    pub quote_volume: String,
+    /// 最优买价，交易所未提供时为空
+    pub bid_pr: String,
+    /// 最优卖价，交易所未提供时为空
+    pub ask_pr: String,
+    /// 最优买卖价的时间（毫秒），未知时为空
+    pub book_ts: String,
+}
+
+impl TickerData {
+    /// 24h 计价币成交额，交易所只提供基础币成交量时按最新价折算
+    pub fn quote_volume(&self) -> Option<Decimal> {
+        if let Ok(quote) = self.quote_volume.parse::<Decimal>() {
+            return Some(quote);
+        }
+        let base = self.base_volume.parse::<Decimal>().ok()?;
+        let price = self.last_pr.parse::<Decimal>().ok()?;
+        base.checked_mul(price)
+    }
+}
+
+/// WebSocket 客户端推送的行情更新
+#[derive(Debug, Clone)]
+pub struct PriceUpdate {
+    /// 交易所名称，例如 Binance
+    pub exchange: String,
+    /// 系统内的交易对名称（symbol.symbol_name）
+    pub symbol: String,
+    pub ticker: TickerData,
+}
+
+impl PriceUpdate {
+    /// 公式中引用该行情的 key，例如 "Binance.BTCUSDT"
+    pub fn key(&self) -> String {
+        format!("{}.{}", self.exchange, self.symbol)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -27,15 +68,247 @@ pub struct IndexConfig {
     pub name: String,
     pub formula: String,
     pub is_active: bool,
+    /// 行情最大延迟（毫秒），交易所时间或本地接收时间超过该值的行情不参与计算，0 表示不检查
+    pub max_age_ms: i64,
+    /// 发布指数所需的最少有效行情数，不足时保持上一次的值并标记为降级
+    pub min_sources: i32,
+    /// EDP（时间加权平均）窗口（秒），0 表示不计算
+    pub edp_window_secs: i32,
+    /// 熔断：单次计算最大相对变动（如 0.02 表示 2%），0 表示不检查
+    pub breaker_max_tick_move: Decimal,
+    /// 熔断：一分钟内最大相对变动，0 表示不检查
+    pub breaker_max_minute_move: Decimal,
+    /// 熔断后连续确认多少次新价格自动恢复，0 表示只能手动恢复
+    pub breaker_confirm_ticks: i32,
+    /// 偏离中位数超过该比例的行情被剔除（如 0.003 表示 0.3%），0 表示不剔除
+    pub outlier_margin: Decimal,
+    /// 生成的 K 线周期，逗号分隔，如 "1m,5m,1h"
+    pub kline_intervals: String,
+    /// 持久化 index_data 的间隔（秒）
+    pub persist_interval_secs: i32,
+    /// 输入行情没有变化时的最长计算间隔（毫秒），用于过期检查、K 线和持久化
+    pub calc_interval_ms: i32,
+    /// 输入行情变化触发重新计算时的最短发布间隔（毫秒）
+    pub min_publish_interval_ms: i32,
+    /// 指数保留的小数位数
+    pub scale: i32,
+    /// 小数位截取方式
+    pub rounding_strategy: RoundingMode,
+    /// 派生的平滑序列，逗号分隔，如 "EMA(30s),SMA(5m)"
+    pub derived_series: String,
+    /// 计价币换算，如 "mul:USDTUSD"、"div:USDTUSD"、"inverse"，空表示不换算
+    pub quote_conversion: String,
+    /// 计划启用时间，新指数的首个公式版本尚未生效时设置，启用后清空
+    pub activate_at: Option<DateTime<Utc>>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
+    /// 当前生效的公式版本（index_config_version.id），没有版本记录时为 None
+    #[sqlx(default)]
+    #[serde(default)]
+    pub version_id: Option<i64>,
+}
+
+/// 指数公式的历史版本，effective_from 之后生效
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct IndexConfigVersion {
+    pub id: i64,
+    pub index_name: String,
+    pub formula: String,
+    pub effective_from: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl IndexConfig {
+    /// 解析 kline_intervals，无法识别的周期被忽略
+    pub fn kline_intervals(&self) -> Vec<KlineInterval> {
+        KlineInterval::parse_list(&self.name, &self.kline_intervals)
+    }
+
+    /// 解析 quote_conversion，无法识别时不换算
+    pub fn quote_conversion(&self) -> Option<QuoteConversion> {
+        let text = self.quote_conversion.trim();
+        if text.is_empty() {
+            return None;
+        }
+        let conversion = QuoteConversion::parse(text);
+        if conversion.is_none() {
+            tracing::warn!("name {} unknown quote conversion {}", self.name, text);
+        }
+        conversion
+    }
+
+    /// 解析 derived_series，无法识别的序列被忽略
+    pub fn derived_series(&self) -> Vec<DerivedSeries> {
+        self.derived_series
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                let series = DerivedSeries::parse(s);
+                if series.is_none() {
+                    tracing::warn!("name {} unknown derived series {}", self.name, s);
+                }
+                series
+            })
+            .collect()
+    }
+}
+
+/// 计价币换算，在公式计算之后、熔断 / EDP / K 线之前应用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuoteConversion {
+    /// 乘以另一个指数，如 BTCUSDT × USDTUSD = BTCUSD
+    Multiply(String),
+    /// 除以另一个指数
+    Divide(String),
+    /// 取倒数，如 BTCUSD -> USDBTC
+    Inverse,
+}
+
+impl QuoteConversion {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.split_once(':') {
+            Some(("mul", index)) if !index.trim().is_empty() => Some(QuoteConversion::Multiply(index.trim().to_string())),
+            Some(("div", index)) if !index.trim().is_empty() => Some(QuoteConversion::Divide(index.trim().to_string())),
+            None if text == "inverse" => Some(QuoteConversion::Inverse),
+            _ => None,
+        }
+    }
+
+    /// 换算引用的指数，需要排在本指数之前计算
+    pub fn index_ref(&self) -> Option<&str> {
+        match self {
+            QuoteConversion::Multiply(index) | QuoteConversion::Divide(index) => Some(index),
+            QuoteConversion::Inverse => None,
+        }
+    }
+}
+
+impl Display for QuoteConversion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuoteConversion::Multiply(index) => write!(f, "mul:{}", index),
+            QuoteConversion::Divide(index) => write!(f, "div:{}", index),
+            QuoteConversion::Inverse => write!(f, "inverse"),
+        }
+    }
+}
+
+/// 平滑方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SmoothingKind {
+    /// 指数移动平均，alpha = min(1, dt / window)
+    Ema,
+    /// 窗口内发布值的时间加权平均
+    Sma,
+}
+
+/// 由原始指数派生的平滑序列，如 EMA(30s)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DerivedSeries {
+    pub kind: SmoothingKind,
+    pub window_secs: i64,
+}
+
+impl DerivedSeries {
+    /// 解析 "EMA(30s)"、"SMA(5m)"，窗口单位支持 s / m / h
+    pub fn parse(text: &str) -> Option<Self> {
+        let (kind, rest) = text.split_once('(')?;
+        let kind = match kind.trim().to_ascii_uppercase().as_str() {
+            "EMA" => SmoothingKind::Ema,
+            "SMA" => SmoothingKind::Sma,
+            _ => return None,
+        };
+        let window = rest.strip_suffix(')')?.trim();
+        let unit = match window.chars().last()? {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            _ => return None,
+        };
+        let value: i64 = window[..window.len() - 1].parse().ok()?;
+        if value <= 0 {
+            return None;
+        }
+        Some(Self {
+            kind,
+            window_secs: value * unit,
+        })
+    }
+
+    /// 派生序列作为独立指数发布时的名称，如 BTCUSDT_EMA_30S
+    pub fn index_name(&self, index_name: &str) -> String {
+        format!("{}_{}_{}", index_name, self.kind_name(), self.window_name()).to_uppercase()
+    }
+
+    fn kind_name(&self) -> &'static str {
+        match self.kind {
+            SmoothingKind::Ema => "EMA",
+            SmoothingKind::Sma => "SMA",
+        }
+    }
+
+    fn window_name(&self) -> String {
+        if self.window_secs % 3600 == 0 {
+            format!("{}h", self.window_secs / 3600)
+        } else if self.window_secs % 60 == 0 {
+            format!("{}m", self.window_secs / 60)
+        } else {
+            format!("{}s", self.window_secs)
+        }
+    }
+}
+
+impl Display for DerivedSeries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({})", self.kind_name(), self.window_name())
+    }
+}
+
+/// 小数位截取方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "varchar")]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingMode {
+    /// 四舍五入
+    #[sqlx(rename = "half_up")]
+    HalfUp,
+    /// 银行家舍入
+    #[sqlx(rename = "half_even")]
+    HalfEven,
+    /// 向零截断
+    #[sqlx(rename = "down")]
+    Down,
+    /// 远离零进位
+    #[sqlx(rename = "up")]
+    Up,
+    /// 向下取整
+    #[sqlx(rename = "floor")]
+    Floor,
+    /// 向上取整
+    #[sqlx(rename = "ceiling")]
+    Ceiling,
+}
+
+impl RoundingMode {
+    pub fn strategy(&self) -> rust_decimal::RoundingStrategy {
+        use rust_decimal::RoundingStrategy;
+        match self {
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::Down => RoundingStrategy::ToZero,
+            RoundingMode::Up => RoundingStrategy::AwayFromZero,
+            RoundingMode::Floor => RoundingStrategy::ToNegativeInfinity,
+            RoundingMode::Ceiling => RoundingStrategy::ToPositiveInfinity,
+        }
+    }
 }
 
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct Task {
     pub id: i64,
     pub exchange_name: String,
-    pub symbol_ids: String, // "1,2,3"
+    pub symbol_ids: String, // "1,2,3"，已弃用，订阅的交易对由启用的公式推导
     pub is_enabled: bool,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
@@ -49,6 +322,9 @@ pub struct Symbol {
     pub third_symbol_name: String,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    /// 公式中以 .bid/.ask/.mid 引用，需要订阅最优买卖价；由公式推导，不是数据库列
+    #[sqlx(default)]
+    pub book: bool,
 }
 
 #[derive(Debug, Clone, FromRow)]
@@ -57,6 +333,22 @@ pub struct IndexData {
     pub symbol: String,
     pub last: Decimal,
     pub formula: String,
+    /// 有效行情数不足 min_sources，last 为保持的上一次的值
+    pub degraded: bool,
+    /// 最近 edp_window_secs 内的时间加权平均
+    pub edp: Option<Decimal>,
+    /// 熔断中，last 为熔断前最后一次发布的值
+    pub breaker_tripped: bool,
+    /// 计算该值的公式版本
+    pub version_id: Option<i64>,
+    /// 换算前的指数值，未配置 quote_conversion 时为 None
+    pub raw_last: Option<Decimal>,
+    /// 换算使用的参考指数值（mul / div），inverse 时为 None
+    pub conversion_rate: Option<Decimal>,
+    /// 因偏离中位数过大被剔除的行情，逗号分隔
+    pub excluded_sources: String,
+    /// 因超过 max_age_ms 未更新被剔除的行情，逗号分隔
+    pub stale_sources: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -76,6 +368,7 @@ pub struct IndexKlineData {
 }
 
 impl IndexKlineData {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: Option<i64>,
         symbol: String,
@@ -103,17 +396,32 @@ impl IndexKlineData {
 
 
 impl IndexData {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: Option<i64>,
         symbol: String,
         last: Decimal,
         formula: String,
+        degraded: bool,
+        edp: Option<Decimal>,
+        breaker_tripped: bool,
+        version_id: Option<i64>,
+        raw_last: Option<Decimal>,
+        conversion_rate: Option<Decimal>,
     ) -> Self {
         Self {
             id,
             symbol,
             last,
             formula,
+            degraded,
+            edp,
+            breaker_tripped,
+            version_id,
+            raw_last,
+            conversion_rate,
+            excluded_sources: String::new(),
+            stale_sources: String::new(),
             created_at: chrono::Utc::now(),
             updated_at: chrono::Utc::now(),
         }
@@ -153,6 +461,32 @@ impl Display for KlineInterval {
 }
 
 impl KlineInterval {
+    /// 解析逗号分隔的周期列表，如 "1m,5m,1h"，无法识别的周期被忽略
+    pub fn parse_list(name: &str, text: &str) -> Vec<Self> {
+        text.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                let interval = KlineInterval::from_name(s);
+                if interval.is_none() {
+                    tracing::warn!("name {} unknown kline interval {}", name, s);
+                }
+                interval
+            })
+            .collect()
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "1m" => Some(KlineInterval::OneMinute),
+            "5m" => Some(KlineInterval::FiveMinutes),
+            "15m" => Some(KlineInterval::FifteenMinutes),
+            "1h" => Some(KlineInterval::OneHour),
+            "4h" => Some(KlineInterval::FourHours),
+            "1d" => Some(KlineInterval::OneDay),
+            _ => None,
+        }
+    }
 
     pub(crate) fn seconds(&self) -> i64 {
         match self {
@@ -167,6 +501,210 @@ impl KlineInterval {
 }
 
 
+/// 熔断事件
+#[derive(Debug, Clone, FromRow)]
+pub struct CircuitBreakerEvent {
+    pub id: Option<i64>,
+    pub index_name: String,
+    /// tripped / resumed / reset
+    pub event: String,
+    /// tick_move / minute_move / confirmed / manual
+    pub reason: String,
+    pub reference_price: Option<Decimal>,
+    pub candidate_price: Option<Decimal>,
+    pub move_ratio: Option<Decimal>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// 结算频率
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, sqlx::Type)]
+#[sqlx(type_name = "varchar")]
+pub enum SettlementFrequency {
+    #[sqlx(rename = "daily")]
+    Daily,
+    #[sqlx(rename = "weekly")]
+    Weekly,
+}
+
+impl Display for SettlementFrequency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettlementFrequency::Daily => write!(f, "daily"),
+            SettlementFrequency::Weekly => write!(f, "weekly"),
+        }
+    }
+}
+
+/// 定时结算配置，例如每天 08:00 UTC、每周五 08:00 UTC
+#[derive(Debug, Clone, FromRow)]
+pub struct SettlementConfig {
+    pub id: i32,
+    pub index_name: String,
+    pub frequency: SettlementFrequency,
+    /// weekly 时的星期几，1 = 周一 ... 7 = 周日
+    pub weekday: Option<i16>,
+    /// 结算时间（UTC）
+    pub settle_time: chrono::NaiveTime,
+    /// 结算价取结算时间前 window_minutes 分钟内指数的平均值
+    pub window_minutes: i32,
+    pub is_active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl SettlementConfig {
+    /// 不晚于 now 的最近一次结算时间
+    pub fn last_settle_at(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive().and_time(self.settle_time).and_utc();
+        let mut at = if today <= now { today } else { today - chrono::Duration::days(1) };
+        if self.frequency == SettlementFrequency::Weekly {
+            let weekday = self.weekday.unwrap_or(5) as u32;
+            while chrono::Datelike::weekday(&at).number_from_monday() != weekday {
+                at -= chrono::Duration::days(1);
+            }
+        }
+        at
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct SettlementPrice {
+    pub id: Option<i64>,
+    pub index_name: String,
+    pub frequency: SettlementFrequency,
+    pub settle_at: DateTime<Utc>,
+    pub price: Decimal,
+    pub window_minutes: i32,
+    /// 窗口内参与平均的指数采样数
+    pub samples_count: i32,
+    /// 窗口内实际参与计算的行情，逗号分隔
+    pub sources: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// 标记价格配置：永续合约价格相对现货指数的溢价
+#[derive(Debug, Clone, FromRow)]
+pub struct MarkPriceConfig {
+    pub id: i32,
+    /// 标记价格名称，不能与指数名称重复
+    pub name: String,
+    /// 引用的现货指数
+    pub index_name: String,
+    /// 永续合约价格公式
+    pub perp_formula: String,
+    /// 溢价 EMA 窗口（秒）
+    pub ema_window_secs: i32,
+    /// 溢价平均窗口（秒）
+    pub premium_window_secs: i32,
+    /// EMA 溢价的上下限，0 表示不限制
+    pub max_premium_rate: Decimal,
+    /// 永续行情最大延迟（毫秒），0 表示不检查
+    pub max_age_ms: i64,
+    /// 资金费周期（秒），从 UTC 0 点起对齐，0 表示不计算资金费率
+    pub funding_interval_secs: i32,
+    /// 每个资金费周期的利率
+    pub interest_rate: Decimal,
+    /// 利率与溢价之差的上下限
+    pub funding_interest_clamp: Decimal,
+    /// 资金费率上下限，0 表示不限制
+    pub max_funding_rate: Decimal,
+    pub is_active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl MarkPriceConfig {
+    /// 不晚于 now 的最近一次资金费结算时间，未配置资金费周期时为 None
+    pub fn last_funding_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if self.funding_interval_secs <= 0 {
+            return None;
+        }
+        let interval = self.funding_interval_secs as i64;
+        DateTime::from_timestamp(now.timestamp() / interval * interval, 0)
+    }
+}
+
+/// 一篮子指数配置：level = Σ(quantity × 成分指数) / divisor
+#[derive(Debug, Clone, FromRow)]
+pub struct BasketConfig {
+    pub id: i32,
+    /// 篮子名称，不能与指数名称重复
+    pub name: String,
+    /// 首次建仓时的点位
+    pub base_level: Decimal,
+    /// 当前除数，尚未建仓时为 None
+    pub divisor: Option<Decimal>,
+    /// 生成的 K 线周期，逗号分隔
+    pub kline_intervals: String,
+    /// 持久化 index_data 的间隔（秒）
+    pub persist_interval_secs: i32,
+    /// 保留的小数位数
+    pub scale: i32,
+    pub is_active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl BasketConfig {
+    pub fn kline_intervals(&self) -> Vec<KlineInterval> {
+        KlineInterval::parse_list(&self.name, &self.kline_intervals)
+    }
+}
+
+/// 篮子成分，weight / quantity 为调仓目标，current_quantity 为最近一次调仓后的实际数量
+#[derive(Debug, Clone, FromRow)]
+pub struct BasketConstituent {
+    pub id: i32,
+    pub basket_name: String,
+    /// 成分指数名称
+    pub index_name: String,
+    pub weight: Option<Decimal>,
+    pub quantity: Option<Decimal>,
+    pub current_quantity: Option<Decimal>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// 资金费率：premium_avg + clamp(interest_rate - premium_avg, ±interest_clamp)，再限制在 ±max_funding_rate 内
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct FundingRate {
+    pub id: Option<i64>,
+    /// 标记价格名称
+    pub name: String,
+    pub index_name: String,
+    /// 资金费结算时间
+    pub funding_at: DateTime<Utc>,
+    pub interval_secs: i32,
+    /// 周期内溢价指数的时间加权平均
+    pub premium_avg: Decimal,
+    /// 周期内溢价采样数
+    pub samples_count: i32,
+    pub interest_rate: Decimal,
+    pub interest_clamp: Decimal,
+    /// clamp(interest_rate - premium_avg, ±interest_clamp)
+    pub interest_component: Decimal,
+    /// 限制前的资金费率 premium_avg + interest_component
+    pub raw_rate: Decimal,
+    pub max_funding_rate: Decimal,
+    pub funding_rate: Decimal,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct MarkPriceData {
+    pub id: Option<i64>,
+    pub name: String,
+    pub index_name: String,
+    pub index_price: Decimal,
+    pub perp_price: Option<Decimal>,
+    pub premium_index: Option<Decimal>,
+    pub premium_avg: Decimal,
+    pub premium_ema: Decimal,
+    pub mark_price: Decimal,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[async_trait::async_trait]
 pub trait ExchangeWsHandler: Send + Sync {
     /// 返回交易所名称
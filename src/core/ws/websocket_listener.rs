@@ -1,4 +1,4 @@
-use crate::core::model::{Exchange, TickerData};
+use crate::core::model::{Exchange, PriceUpdate, TickerData};
 use crate::core::trade::trade_repository::{Trade, TradeRepository};
 use async_trait::async_trait;
 use dashmap::DashMap;
@@ -9,6 +9,7 @@ use std::{
     sync::Arc,
 };
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{Mutex, RwLock};
 use tokio::time::interval;
 use tokio_tungstenite::{
@@ -17,6 +18,9 @@ use tokio_tungstenite::{
 use tracing::{error, info};
 use tungstenite::Utf8Bytes;
 
+/// WebSocket 写端，连接建立后用于发送订阅消息
+pub type WsWriter = Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>;
+
 #[async_trait]
 pub trait WebSocketStatusListener: Send + Sync + 'static {
     fn exchange_name(&self) -> &str;
@@ -39,6 +43,9 @@ pub trait WebSocketStatusListener: Send + Sync + 'static {
     fn store(&self) -> Arc<DashMap<String, TickerData>>;
     fn trade_repo(&self) -> Arc<TradeRepository>;
     fn symbol_map(&self) -> Arc<RwLock<HashMap<String, String>>>;
+    fn price_sender(&self) -> &UnboundedSender<PriceUpdate>;
+    /// 当前连接的写端，未连接时为 None
+    fn writer(&self) -> Arc<RwLock<Option<WsWriter>>>;
 
     /// ---- 通用逻辑（提供默认实现）----
     async fn connect_internal_arc(
@@ -70,6 +77,7 @@ pub trait WebSocketStatusListener: Send + Sync + 'static {
 
         // write 包装成 Arc<Mutex<_>>
         let write = Arc::new(Mutex::new(write));
+        *self.writer().write().await = Some(Arc::clone(&write));
         let this = Arc::clone(&self);
 
         // 心跳任务
@@ -146,11 +154,54 @@ pub trait WebSocketStatusListener: Send + Sync + 'static {
         }
     }
 
+    /// 运行中追加订阅，symbols 为 third_symbol_name -> symbol_name；
+    /// 记录到 sub_symbol_set 供重连时订阅，已连接时立即发送订阅消息
+    async fn subscribe(&self, symbols: HashMap<String, String>) -> anyhow::Result<()> {
+        let added: HashSet<String> = {
+            let sub_set = self.sub_symbol_set();
+            let mut set = sub_set.write().await;
+            symbols.keys().filter(|s| set.insert((*s).clone())).cloned().collect()
+        };
+        self.symbol_map().write().await.extend(symbols);
+        if added.is_empty() {
+            return Ok(());
+        }
+
+        info!("{} subscribing {:?}", self.exchange_name(), added);
+        let writer = self.writer().read().await.clone();
+        let connected = *self.connected().read().await;
+        if let (Some(write), true) = (writer, connected)
+            && let Some(msg) = self.build_sub_msg(&added)
+        {
+            write.lock().await.send(Message::Text(Utf8Bytes::from(msg))).await?;
+        }
+        Ok(())
+    }
+
+    /// 登记需要最优买卖价的 symbol (third_symbol_name)，已订阅行情的 symbol 在连接时立即补订；
+    /// ticker 频道已包含买卖价的交易所不需要实现
+    async fn subscribe_book(&self, _symbols: HashSet<String>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     /// 判断是否连接
     fn is_connected(&self) -> bool {
         futures::executor::block_on(async { *self.connected().read().await })
     }
 
+    /// 保存最新 ticker 并推送给指数计算
+    fn publish_ticker(&self, symbol_name: &str, ticker: TickerData) {
+        self.store().insert(symbol_name.to_string(), ticker.clone());
+        let update = PriceUpdate {
+            exchange: self.exchange_name().to_string(),
+            symbol: symbol_name.to_string(),
+            ticker,
+        };
+        if self.price_sender().send(update).is_err() {
+            error!("{} price channel closed", self.exchange_name());
+        }
+    }
+
     /// 获取最新 ticker
     fn get_ticker(&self, symbol: &str) -> Option<TickerData> {
         self.store().get(symbol).map(|v| v.clone())
@@ -1,6 +1,8 @@
 use std::{collections::{HashSet, HashMap}, sync::Arc};
 use async_trait::async_trait;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{Mutex, RwLock};
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
 use futures_util::{SinkExt, StreamExt};
@@ -9,9 +11,9 @@ use tokio::net::TcpStream;
 use tracing::{info, error};
 use tungstenite::Utf8Bytes;
 
-use crate::core::model::{Exchange, TickerData};
+use crate::core::model::{Exchange, PriceUpdate, TickerData};
 use crate::core::trade::trade_repository::{Trade, TradeRepository};
-use crate::core::ws::websocket_listener::WebSocketStatusListener;
+use crate::core::ws::websocket_listener::{WebSocketStatusListener, WsWriter};
 
 #[derive(Clone)]
 pub struct BinanceWebSocketClient {
@@ -20,30 +22,73 @@ pub struct BinanceWebSocketClient {
     pub sub_symbol_set: Arc<RwLock<HashSet<String>>>,
     pub store: Arc<DashMap<String, TickerData>>,
     connected: Arc<RwLock<bool>>,
+    writer: Arc<RwLock<Option<WsWriter>>>,
     trade_repo: Arc<TradeRepository>,
     ticker_suffix: String,
+    book_ticker_suffix: String,
+    /// 需要订阅 bookTicker 的 symbol (third_symbol_name)，只包含公式中以 .bid/.ask/.mid 引用的
+    book_symbol_set: Arc<DashSet<String>>,
     pub symbol_map: Arc<RwLock<HashMap<String, String>>>,
     pub ping_msg : String,
     pub ping_interval : u64,
+    price_sender: UnboundedSender<PriceUpdate>,
 }
 
 impl BinanceWebSocketClient {
-    pub fn new(exchange: Exchange, trade_repo: Arc<TradeRepository>, symbol_map: HashMap<String, String>, ping_msg: String, ping_interval: u64) -> Self {
+    pub fn new(exchange: Exchange, trade_repo: Arc<TradeRepository>, symbol_map: HashMap<String, String>, ping_msg: String, ping_interval: u64, price_sender: UnboundedSender<PriceUpdate>) -> Self {
         Self {
             exchange: Arc::new(exchange),
             ws_url: "wss://stream.binance.com:443/ws/btcusdt@miniTicker".to_string(),
             sub_symbol_set: Arc::new(RwLock::new(HashSet::new())),
             store: Arc::new(DashMap::new()),
             connected: Arc::new(RwLock::new(false)),
+            writer: Arc::new(RwLock::new(None)),
             trade_repo,
             ticker_suffix: "@miniTicker".to_string(),
+            book_ticker_suffix: "@bookTicker".to_string(),
+            book_symbol_set: Arc::new(DashSet::new()),
             symbol_map: Arc::new(RwLock::new(symbol_map)),
             ping_msg,
             ping_interval,
+            price_sender,
         }
     }
 }
 
+/// bookTicker 推送，没有事件类型字段，只有 u(updateId)/s/b/B/a/A
+#[derive(Debug, Deserialize)]
+struct BookTicker {
+    #[serde(rename = "u")]
+    _update_id: u64,
+    #[serde(rename = "s")]
+    symbol: String,
+    #[serde(rename = "b")]
+    bid: String,
+    #[serde(rename = "a")]
+    ask: String,
+}
+
+impl BinanceWebSocketClient {
+    /// bookTicker 只推送最优买卖价，合并到已有的 ticker 中；没有交易所时间，使用本地接收时间
+    async fn handle_book_ticker(&self, book: BookTicker) {
+        let symbol_map_lock = self.symbol_map();
+        let symbol_map = symbol_map_lock.read().await;
+        let symbol_name = symbol_map.get(&book.symbol).unwrap_or(&book.symbol).to_string();
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut ticker = self.store().get(&symbol_name).map(|t| t.clone()).unwrap_or_else(|| TickerData {
+            inst_id: book.symbol.clone(),
+            ..Default::default()
+        });
+        ticker.bid_pr = book.bid;
+        ticker.ask_pr = book.ask;
+        ticker.book_ts = now.to_string();
+        ticker.received_at = now;
+
+        self.publish_ticker(&symbol_name, ticker);
+    }
+}
+
 #[async_trait]
 impl WebSocketStatusListener for BinanceWebSocketClient {
     fn exchange_name(&self) -> &str {
@@ -68,7 +113,14 @@ impl WebSocketStatusListener for BinanceWebSocketClient {
         }
         let args: Vec<_> = symbols
             .iter()
-            .map(|s| format!("{}{}", s.to_lowercase(), self.ticker_suffix))
+            .flat_map(|s| {
+                let stream = s.to_lowercase();
+                let mut args = vec![format!("{}{}", stream, self.ticker_suffix)];
+                if self.book_symbol_set.contains(s) {
+                    args.push(format!("{}{}", stream, self.book_ticker_suffix));
+                }
+                args
+            })
             .collect();
         Some(serde_json::json!({
             "method": "SUBSCRIBE",
@@ -79,6 +131,10 @@ impl WebSocketStatusListener for BinanceWebSocketClient {
 
     async fn handle_message(&self, text: &str, write: Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>) {
         if !text.contains("MiniTicker") {
+            if let Ok(book) = serde_json::from_str::<BookTicker>(text) {
+                self.handle_book_ticker(book).await;
+                return;
+            }
             if text.contains("ping") {
                 let pong_msg = text.replace("ping", "pong");
                 let _ = write
@@ -94,19 +150,29 @@ impl WebSocketStatusListener for BinanceWebSocketClient {
             let symbol = json_val["s"].as_str().unwrap_or_default();
             let price = json_val["c"].as_str().unwrap_or_default();
             let ts = json_val["E"].as_i64().unwrap_or(0);
+            let base_volume = json_val["v"].as_str().unwrap_or_default();
+            let quote_volume = json_val["q"].as_str().unwrap_or_default();
 
+            // ✅ 先绑定 Arc，避免临时值被释放
+            let symbol_map_lock = self.symbol_map();
+            let symbol_map = symbol_map_lock.read().await;
+            let symbol_name = symbol_map.get(symbol).unwrap_or(&String::from(symbol)).to_string();
+
+            // 保留 bookTicker 推送的最优买卖价
+            let book = self.store().get(&symbol_name).map(|t| t.clone()).unwrap_or_default();
             let ticker = TickerData {
                 last_pr: price.to_string(),
                 inst_id: symbol.to_string(),
                 ts: ts.to_string(),
+                received_at: chrono::Utc::now().timestamp_millis(),
+                base_volume: base_volume.to_string(),
+                quote_volume: quote_volume.to_string(),
+                bid_pr: book.bid_pr,
+                ask_pr: book.ask_pr,
+                book_ts: book.book_ts,
             };
 
-            // ✅ 先绑定 Arc，避免临时值被释放
-            let symbol_map_lock = self.symbol_map();
-            let symbol_map = symbol_map_lock.read().await;
-            let symbol_name = symbol_map.get(symbol).unwrap_or(&String::from(symbol)).to_string();
-
-            self.store().insert(symbol_name.clone(), ticker.clone());
+            self.publish_ticker(&symbol_name, ticker.clone());
 
             if let Ok(p) = price.parse::<f64>() {
                 let trade = Trade {
@@ -120,10 +186,43 @@ impl WebSocketStatusListener for BinanceWebSocketClient {
         }
     }
 
+    /// 登记需要 bookTicker 的 symbol，已订阅 miniTicker 且已连接时立即补订
+    async fn subscribe_book(&self, symbols: HashSet<String>) -> anyhow::Result<()> {
+        let added: Vec<String> = symbols.into_iter().filter(|s| self.book_symbol_set.insert(s.clone())).collect();
+        let subscribed: Vec<String> = {
+            let sub_set = self.sub_symbol_set.read().await;
+            added.into_iter().filter(|s| sub_set.contains(s)).collect()
+        };
+        if subscribed.is_empty() {
+            return Ok(());
+        }
+
+        info!("{} subscribing bookTicker {:?}", self.exchange.name, subscribed);
+        let writer = self.writer.read().await.clone();
+        let connected = *self.connected.read().await;
+        if let (Some(write), true) = (writer, connected) {
+            let args: Vec<String> = subscribed
+                .iter()
+                .map(|s| format!("{}{}", s.to_lowercase(), self.book_ticker_suffix))
+                .collect();
+            let msg = serde_json::json!({
+                "method": "SUBSCRIBE",
+                "params": args,
+                "id": chrono::Utc::now().timestamp_millis()
+            }).to_string();
+            write.lock().await.send(Message::Text(Utf8Bytes::from(msg))).await?;
+        }
+        Ok(())
+    }
+
     fn connected(&self) -> Arc<RwLock<bool>> {
         Arc::clone(&self.connected)
     }
 
+    fn writer(&self) -> Arc<RwLock<Option<WsWriter>>> {
+        Arc::clone(&self.writer)
+    }
+
     fn sub_symbol_set(&self) -> Arc<RwLock<HashSet<String>>> {
         Arc::clone(&self.sub_symbol_set)
     }
@@ -136,6 +235,10 @@ impl WebSocketStatusListener for BinanceWebSocketClient {
         Arc::clone(&self.trade_repo)
     }
 
+    fn price_sender(&self) -> &UnboundedSender<PriceUpdate> {
+        &self.price_sender
+    }
+
     fn symbol_map(&self) -> Arc<RwLock<HashMap<String, String>>> {
         Arc::clone(&self.symbol_map)
     }
@@ -1,6 +1,7 @@
 use std::{collections::{HashSet, HashMap}, sync::Arc};
 use async_trait::async_trait;
 use dashmap::DashMap;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{Mutex, RwLock};
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
 use futures_util::{SinkExt, StreamExt};
@@ -9,9 +10,9 @@ use tracing::{info, error};
 use tungstenite::Utf8Bytes;
 use serde_json::json;
 use tokio::net::TcpStream;
-use crate::core::model::{Exchange, TickerData};
+use crate::core::model::{Exchange, PriceUpdate, TickerData};
 use crate::core::trade::trade_repository::{Trade, TradeRepository};
-use crate::core::ws::websocket_listener::WebSocketStatusListener;
+use crate::core::ws::websocket_listener::{WebSocketStatusListener, WsWriter};
 
 #[derive(Clone)]
 pub struct BitgetWebSocketClient {
@@ -20,24 +21,28 @@ pub struct BitgetWebSocketClient {
     pub sub_symbol_set: Arc<RwLock<HashSet<String>>>,
     pub store: Arc<DashMap<String, TickerData>>,
     connected: Arc<RwLock<bool>>,
+    writer: Arc<RwLock<Option<WsWriter>>>,
     trade_repo: Arc<TradeRepository>,
     pub symbol_map: Arc<RwLock<HashMap<String, String>>>,
     pub ping_msg : String,
     pub ping_interval : u64,
+    price_sender: UnboundedSender<PriceUpdate>,
 }
 
 impl BitgetWebSocketClient {
-    pub fn new(exchange: Exchange, trade_repo: Arc<TradeRepository>, symbol_map: HashMap<String, String>, ping_msg: String, ping_interval: u64) -> Self {
+    pub fn new(exchange: Exchange, trade_repo: Arc<TradeRepository>, symbol_map: HashMap<String, String>, ping_msg: String, ping_interval: u64, price_sender: UnboundedSender<PriceUpdate>) -> Self {
         Self {
             exchange: Arc::new(exchange),
             ws_url: "wss://ws.bitget.com/v2/ws/public".to_string(),
             sub_symbol_set: Arc::new(RwLock::new(HashSet::new())),
             store: Arc::new(DashMap::new()),
             connected: Arc::new(RwLock::new(false)),
+            writer: Arc::new(RwLock::new(None)),
             trade_repo,
             symbol_map: Arc::new(RwLock::new(symbol_map)),
             ping_msg,
             ping_interval,
+            price_sender,
         }
     }
 }
@@ -62,8 +67,12 @@ impl WebSocketStatusListener for BitgetWebSocketClient {
 
     fn build_sub_msg(&self, symbols: &HashSet<String>) -> Option<String> {
         if symbols.is_empty() { return None; }
+        // 合约写作 "USDT-FUTURES:BTCUSDT"，不带前缀的为现货
         let args: Vec<_> = symbols.iter()
-            .map(|s| json!({"instType":"SPOT","channel":"ticker","instId": s}))
+            .map(|s| {
+                let (inst_type, inst_id) = s.split_once(':').unwrap_or(("SPOT", s));
+                json!({"instType": inst_type,"channel":"ticker","instId": inst_id})
+            })
             .collect();
         Some(json!({"op":"subscribe","args": args}).to_string())
     }
@@ -72,6 +81,7 @@ impl WebSocketStatusListener for BitgetWebSocketClient {
         if !text.contains("ticker") { return; }
 
         if let Ok(json_val) = serde_json::from_str::<serde_json::Value>(text) {
+            let inst_type = json_val["arg"]["instType"].as_str().unwrap_or("SPOT");
             if let Some(data_array) = json_val["data"].as_array() {
                 for d in data_array {
                     if let (Some(last), Some(inst_id), Some(ts)) =
@@ -81,13 +91,25 @@ impl WebSocketStatusListener for BitgetWebSocketClient {
                             last_pr: last.to_string(),
                             inst_id: inst_id.to_string(),
                             ts: ts.to_string(),
+                            received_at: chrono::Utc::now().timestamp_millis(),
+                            base_volume: d["baseVolume"].as_str().unwrap_or_default().to_string(),
+                            bid_pr: d["bidPr"].as_str().unwrap_or_default().to_string(),
+                            ask_pr: d["askPr"].as_str().unwrap_or_default().to_string(),
+                            book_ts: ts.to_string(),
+                            quote_volume: d["quoteVolume"].as_str().unwrap_or_default().to_string(),
                         };
 
+                        // 现货与合约的 instId 相同，合约按 "instType:instId" 查找
+                        let third_symbol = if inst_type == "SPOT" {
+                            inst_id.to_string()
+                        } else {
+                            format!("{}:{}", inst_type, inst_id)
+                        };
                         let symbol_map_lock = self.symbol_map();
                         let symbol_map = symbol_map_lock.read().await;
-                        let symbol_name = symbol_map.get(inst_id).unwrap_or(&inst_id.to_string()).to_string();
+                        let symbol_name = symbol_map.get(&third_symbol).unwrap_or(&third_symbol).to_string();
 
-                        self.store().insert(symbol_name.clone(), ticker.clone());
+                        self.publish_ticker(&symbol_name, ticker.clone());
 
                         if let Ok(price) = last.parse::<f64>() {
                             let trade = Trade {
@@ -108,6 +130,10 @@ impl WebSocketStatusListener for BitgetWebSocketClient {
         Arc::clone(&self.connected)
     }
 
+    fn writer(&self) -> Arc<RwLock<Option<WsWriter>>> {
+        Arc::clone(&self.writer)
+    }
+
     fn sub_symbol_set(&self) -> Arc<RwLock<HashSet<String>>> {
         Arc::clone(&self.sub_symbol_set)
     }
@@ -120,6 +146,10 @@ impl WebSocketStatusListener for BitgetWebSocketClient {
         Arc::clone(&self.trade_repo)
     }
 
+    fn price_sender(&self) -> &UnboundedSender<PriceUpdate> {
+        &self.price_sender
+    }
+
     fn symbol_map(&self) -> Arc<RwLock<HashMap<String, String>>> {
         Arc::clone(&self.symbol_map)
     }
@@ -1,6 +1,7 @@
 use std::{collections::{HashSet, HashMap}, sync::Arc};
 use async_trait::async_trait;
 use dashmap::DashMap;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{Mutex, RwLock};
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
 use futures_util::{SinkExt, StreamExt};
@@ -9,10 +10,11 @@ use tracing::{info, error};
 use serde_json::json;
 use tokio::net::TcpStream;
 use tungstenite::Utf8Bytes;
+use rust_decimal::Decimal;
 
-use crate::core::model::{Exchange, TickerData};
+use crate::core::model::{Exchange, PriceUpdate, TickerData};
 use crate::core::trade::trade_repository::{Trade, TradeRepository};
-use crate::core::ws::websocket_listener::WebSocketStatusListener;
+use crate::core::ws::websocket_listener::{WebSocketStatusListener, WsWriter};
 
 #[derive(Clone)]
 pub struct OkexWebSocketClient {
@@ -21,24 +23,28 @@ pub struct OkexWebSocketClient {
     pub sub_symbol_set: Arc<RwLock<HashSet<String>>>,
     pub store: Arc<DashMap<String, TickerData>>,
     connected: Arc<RwLock<bool>>,
+    writer: Arc<RwLock<Option<WsWriter>>>,
     trade_repo: Arc<TradeRepository>,
     pub symbol_map: Arc<RwLock<HashMap<String, String>>>,
     pub ping_msg : String,
     pub ping_interval : u64,
+    price_sender: UnboundedSender<PriceUpdate>,
 }
 
 impl OkexWebSocketClient {
-    pub fn new(exchange: Exchange, trade_repo: Arc<TradeRepository>, symbol_map: HashMap<String, String>, ping_msg: String, ping_interval: u64) -> Self {
+    pub fn new(exchange: Exchange, trade_repo: Arc<TradeRepository>, symbol_map: HashMap<String, String>, ping_msg: String, ping_interval: u64, price_sender: UnboundedSender<PriceUpdate>) -> Self {
         Self {
             exchange: Arc::new(exchange),
             ws_url: "wss://ws.okx.com:8443/ws/v5/public".to_string(),
             sub_symbol_set: Arc::new(RwLock::new(HashSet::new())),
             store: Arc::new(DashMap::new()),
             connected: Arc::new(RwLock::new(false)),
+            writer: Arc::new(RwLock::new(None)),
             trade_repo,
             symbol_map: Arc::new(RwLock::new(symbol_map)),
             ping_msg,
             ping_interval,
+            price_sender,
         }
     }
 }
@@ -72,33 +78,39 @@ impl WebSocketStatusListener for OkexWebSocketClient {
     async fn handle_message(&self, text: &str, write: Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>) {
         if !text.contains("tickers") { return; }
 
-        if let Ok(json_val) = serde_json::from_str::<serde_json::Value>(text) {
-            if let Some(data_array) = json_val["data"].as_array() {
-                for d in data_array {
-                    if let (Some(last), Some(inst_id), Some(ts)) =
-                        (d["last"].as_str(), d["instId"].as_str(), d["ts"].as_str())
-                    {
-                        let ticker = TickerData {
-                            last_pr: last.to_string(),
-                            inst_id: inst_id.to_string(),
-                            ts: ts.to_string(),
+        if let Ok(json_val) = serde_json::from_str::<serde_json::Value>(text)
+            && let Some(data_array) = json_val["data"].as_array()
+        {
+            for d in data_array {
+                if let (Some(last), Some(inst_id), Some(ts)) =
+                    (d["last"].as_str(), d["instId"].as_str(), d["ts"].as_str())
+                {
+                    let ticker = TickerData {
+                        last_pr: last.to_string(),
+                        inst_id: inst_id.to_string(),
+                        ts: ts.to_string(),
+                        received_at: chrono::Utc::now().timestamp_millis(),
+                        base_volume: base_volume(inst_id, d["vol24h"].as_str().unwrap_or_default(), d["volCcy24h"].as_str().unwrap_or_default()),
+                        bid_pr: d["bidPx"].as_str().unwrap_or_default().to_string(),
+                        ask_pr: d["askPx"].as_str().unwrap_or_default().to_string(),
+                        book_ts: ts.to_string(),
+                        quote_volume: quote_volume(inst_id, last, d["volCcy24h"].as_str().unwrap_or_default()),
+                    };
+
+                    let symbol_map_lock = self.symbol_map();
+                    let symbol_map = symbol_map_lock.read().await;
+                    let symbol_name = symbol_map.get(inst_id).unwrap_or(&inst_id.to_string()).to_string();
+
+                    self.publish_ticker(&symbol_name, ticker.clone());
+
+                    if let Ok(price) = last.parse::<f64>() {
+                        let trade = Trade {
+                            exchange: self.exchange_name().to_string(),
+                            symbol: symbol_name,
+                            price,
+                            timestamp: ts.parse().unwrap_or(0),
                         };
-
-                        let symbol_map_lock = self.symbol_map();
-                        let symbol_map = symbol_map_lock.read().await;
-                        let symbol_name = symbol_map.get(inst_id).unwrap_or(&inst_id.to_string()).to_string();
-
-                        self.store().insert(symbol_name.clone(), ticker.clone());
-
-                        if let Ok(price) = last.parse::<f64>() {
-                            let trade = Trade {
-                                exchange: self.exchange_name().to_string(),
-                                symbol: symbol_name,
-                                price,
-                                timestamp: ts.parse().unwrap_or(0),
-                            };
-                            self.trade_repo().save_trade(trade);
-                        }
+                        self.trade_repo().save_trade(trade);
                     }
                 }
             }
@@ -109,6 +121,10 @@ impl WebSocketStatusListener for OkexWebSocketClient {
         Arc::clone(&self.connected)
     }
 
+    fn writer(&self) -> Arc<RwLock<Option<WsWriter>>> {
+        Arc::clone(&self.writer)
+    }
+
     fn sub_symbol_set(&self) -> Arc<RwLock<HashSet<String>>> {
         Arc::clone(&self.sub_symbol_set)
     }
@@ -121,7 +137,31 @@ impl WebSocketStatusListener for OkexWebSocketClient {
         Arc::clone(&self.trade_repo)
     }
 
+    fn price_sender(&self) -> &UnboundedSender<PriceUpdate> {
+        &self.price_sender
+    }
+
     fn symbol_map(&self) -> Arc<RwLock<HashMap<String, String>>> {
         Arc::clone(&self.symbol_map)
     }
 }
+
+/// 24h 基础币成交量：现货的 vol24h 为基础币，永续合约的 vol24h 为合约张数，使用 volCcy24h
+fn base_volume(inst_id: &str, vol: &str, vol_ccy: &str) -> String {
+    if inst_id.ends_with("-SWAP") {
+        vol_ccy.to_string()
+    } else {
+        vol.to_string()
+    }
+}
+
+/// 24h 计价币成交额：现货的 volCcy24h 为计价币，永续合约（instId 以 -SWAP 结尾）的 volCcy24h 为基础币，需乘以最新价
+fn quote_volume(inst_id: &str, last: &str, vol_ccy: &str) -> String {
+    if !inst_id.ends_with("-SWAP") {
+        return vol_ccy.to_string();
+    }
+    match (vol_ccy.parse::<Decimal>(), last.parse::<Decimal>()) {
+        (Ok(volume), Ok(price)) => (volume * price).normalize().to_string(),
+        _ => String::new(),
+    }
+}
@@ -19,8 +19,15 @@ async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let pool = init_pool_for_postgres().await?;
     let config_repo = ConfigRepository::new(pool.clone());
+    config_repo.migrate_index_config().await?;
+    // 停机期间到达启用时间的新指数
+    for name in config_repo.activate_scheduled_configs().await? {
+        tracing::info!("Index {} activated by schedule", name);
+    }
     let index_configs = config_repo.get_active_configs().await?;
     init_index_data_table_for_config(&config_repo, &index_configs).await?;
+    init_mark_price_table_for_config(&config_repo).await?;
+    init_basket_table_for_config(&config_repo).await?;
     let app = App::new(pool.clone()).await?;
     let config_repo_arc = Arc::new(config_repo);
     let (kline_tx, kline_rx) = tokio::sync::mpsc::unbounded_channel();
@@ -32,6 +39,23 @@ async fn main() -> anyhow::Result<()> {
 async fn init_index_data_table_for_config(config_repo: &ConfigRepository, index_configs: &Vec<IndexConfig>) -> anyhow::Result<()> {
     for config in index_configs {
         config_repo.create_index_data_table_if_not_exists(&config.name).await?;
+        for series in config.derived_series() {
+            config_repo.create_index_data_table_if_not_exists(&series.index_name(&config.name)).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn init_mark_price_table_for_config(config_repo: &ConfigRepository) -> anyhow::Result<()> {
+    for config in config_repo.get_active_mark_price_configs().await? {
+        config_repo.create_mark_price_table_if_not_exists(&config.name).await?;
     }
     Ok(())
 }
+
+async fn init_basket_table_for_config(config_repo: &ConfigRepository) -> anyhow::Result<()> {
+    for config in config_repo.get_active_basket_configs().await? {
+        config_repo.create_index_data_table_if_not_exists(&config.name).await?;
+    }
+    Ok(())
+}
\ No newline at end of file
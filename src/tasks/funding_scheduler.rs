@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use tokio::time::{sleep, Duration};
+use crate::core::db::config_repository::ConfigRepository;
+use crate::core::index::calculator_manager::CalculatorManager;
+use crate::core::model::{FundingRate, MarkPriceConfig};
+use tracing::{error, info, warn};
+
+/// 资金费率：每个资金费周期结束时，取周期内溢价指数的时间加权平均 P，
+/// funding = clamp(P + clamp(interest_rate - P, ±interest_clamp), ±max_funding_rate)，写入 funding_rate
+pub async fn run_funding_scheduler(
+    calculators: Arc<CalculatorManager>,
+    mark_configs: Vec<MarkPriceConfig>,
+    config_repo: Arc<ConfigRepository>,
+) {
+    let mark_configs: Vec<MarkPriceConfig> = mark_configs
+        .into_iter()
+        .filter(|c| c.funding_interval_secs > 0)
+        .collect();
+    if mark_configs.is_empty() {
+        return;
+    }
+
+    // 每个标记价格上次结算的时间，启动时不补算已经过去的周期
+    let now = Utc::now();
+    let mut last_funded: HashMap<i32, DateTime<Utc>> = mark_configs
+        .iter()
+        .filter_map(|c| Some((c.id, c.last_funding_at(now)?)))
+        .collect();
+
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        let now = Utc::now();
+
+        for config in &mark_configs {
+            let Some(funding_at) = config.last_funding_at(now) else {
+                continue;
+            };
+            if last_funded.get(&config.id) == Some(&funding_at) {
+                continue;
+            }
+            last_funded.insert(config.id, funding_at);
+
+            let to_ms = funding_at.timestamp_millis();
+            let from_ms = to_ms - config.funding_interval_secs as i64 * 1000;
+            let Some((premium_avg, samples_count)) = calculators.premium_between(&config.name, from_ms, to_ms).await else {
+                warn!(
+                    "Funding {} at {} skipped: no premium samples in last {} seconds",
+                    config.name, funding_at, config.funding_interval_secs
+                );
+                continue;
+            };
+
+            let interest_clamp = config.funding_interest_clamp.abs();
+            let (interest_component, raw_rate, funding_rate) =
+                compute_funding_rate(premium_avg, config.interest_rate, interest_clamp, config.max_funding_rate);
+
+            let rate = FundingRate {
+                id: None,
+                name: config.name.clone(),
+                index_name: config.index_name.clone(),
+                funding_at,
+                interval_secs: config.funding_interval_secs,
+                premium_avg,
+                samples_count: samples_count as i32,
+                interest_rate: config.interest_rate,
+                interest_clamp,
+                interest_component,
+                raw_rate,
+                max_funding_rate: config.max_funding_rate,
+                funding_rate,
+                created_at: now,
+            };
+            info!(
+                "Funding {} at {} = {}, premium_avg: {}, interest: {}, samples: {}",
+                rate.name, funding_at, funding_rate, premium_avg, interest_component, rate.samples_count
+            );
+
+            let config_repo = config_repo.clone();
+            tokio::spawn(async move {
+                if let Err(e) = config_repo.insert_funding_rate(&rate).await {
+                    error!("Error saving funding rate: {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+/// 返回 (interest_component, raw_rate, funding_rate)，max_funding_rate <= 0 表示不限制
+fn compute_funding_rate(
+    premium_avg: Decimal,
+    interest_rate: Decimal,
+    interest_clamp: Decimal,
+    max_funding_rate: Decimal,
+) -> (Decimal, Decimal, Decimal) {
+    let interest_component = (interest_rate - premium_avg).clamp(-interest_clamp, interest_clamp);
+    let raw_rate = premium_avg + interest_component;
+    let funding_rate = if max_funding_rate > Decimal::ZERO {
+        raw_rate.clamp(-max_funding_rate, max_funding_rate)
+    } else {
+        raw_rate
+    };
+    (interest_component, raw_rate, funding_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn interest_component_within_clamp_is_kept() {
+        let (interest, raw, rate) = compute_funding_rate(dec("0.0001"), dec("0.0003"), dec("0.0005"), dec("0.0075"));
+        assert_eq!(interest, dec("0.0002"));
+        assert_eq!(raw, dec("0.0003"));
+        assert_eq!(rate, dec("0.0003"));
+    }
+
+    #[test]
+    fn interest_component_is_clamped() {
+        let (interest, raw, _) = compute_funding_rate(dec("0.002"), dec("0.0001"), dec("0.0005"), dec("0.0075"));
+        assert_eq!(interest, dec("-0.0005"));
+        assert_eq!(raw, dec("0.0015"));
+
+        let (interest, raw, _) = compute_funding_rate(dec("-0.002"), dec("0.0001"), dec("0.0005"), dec("0.0075"));
+        assert_eq!(interest, dec("0.0005"));
+        assert_eq!(raw, dec("-0.0015"));
+    }
+
+    #[test]
+    fn funding_rate_is_clamped_to_max() {
+        let (_, raw, rate) = compute_funding_rate(dec("0.01"), dec("0.0001"), dec("0.0005"), dec("0.0075"));
+        assert_eq!(raw, dec("0.0095"));
+        assert_eq!(rate, dec("0.0075"));
+
+        let (_, raw, rate) = compute_funding_rate(dec("-0.01"), dec("0.0001"), dec("0.0005"), dec("0.0075"));
+        assert_eq!(raw, dec("-0.0095"));
+        assert_eq!(rate, dec("-0.0075"));
+    }
+
+    #[test]
+    fn zero_max_funding_rate_disables_clamp() {
+        let (_, raw, rate) = compute_funding_rate(dec("0.01"), dec("0.0001"), dec("0.0005"), Decimal::ZERO);
+        assert_eq!(rate, raw);
+        assert_eq!(rate, dec("0.0095"));
+    }
+}
@@ -1,148 +1,462 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::time::{sleep, Duration, Instant};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::{Duration, Instant, MissedTickBehavior};
 use crate::core::db::config_repository::ConfigRepository;
-use crate::core::index::calculator_manager::CalculatorManager;
-use crate::core::model::{IndexConfig, IndexData, IndexKlineData, KlineInterval};
-use rust_decimal::Decimal;
+use crate::core::exchange::exchange_initializer::ExchangeInitializer;
+use crate::core::exchange::exchange_manager::ExchangeManager;
+use crate::core::index::basket_calculator::{BasketCalculator, BasketRebalance, BasketTarget};
+use crate::core::index::calculator_manager::{conversion_deps, CalculatorManager};
+use crate::core::index::circuit_breaker::{BreakerEvent, BreakerEventKind};
+use crate::core::index::formula;
+use crate::core::index::index_calculator::{Index, IndexCalculator};
+use crate::core::index::smoothed_series::SmoothedSeries;
+use crate::core::model::{BasketConfig, CircuitBreakerEvent, IndexConfig, IndexConfigVersion, IndexData, IndexKlineData, KlineInterval, MarkPriceData};
+use rust_decimal::{Decimal, RoundingStrategy};
 
 use std::collections::hash_map::Entry;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 
+/// 周期 OHLC (aligned_ts, open, high, low, close)
+type Ohlc = (i64, Decimal, Decimal, Decimal, Decimal);
+
+/// calc_interval_ms 的下限（毫秒）
+const MIN_CALC_INTERVAL_MS: i32 = 100;
+/// 检查到期指数的最短轮询间隔（毫秒）
+const MIN_TICK_MS: i32 = 10;
+/// 检查手动恢复熔断请求和新公式版本的间隔（毫秒）
+const DB_POLL_MS: i64 = 5_000;
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_index_calculator(
     calculators: Arc<CalculatorManager>,
-    index_configs: Vec<IndexConfig>,
+    mut index_configs: Vec<IndexConfig>,
     config_repo: Arc<ConfigRepository>,
     kline_sender: UnboundedSender<IndexKlineData>,
-    intervals: Vec<KlineInterval>, // 支持多周期
+    mut changed_rx: UnboundedReceiver<String>,
+    mut pending_versions: Vec<IndexConfigVersion>,
+    mut last_version_id: i64,
+    basket_configs: Vec<BasketConfig>,
+    manager: Arc<ExchangeManager>,
 ) {
-    // 每个指数的周期 OHLC (aligned_ts, open, high, low, close)
-    let mut ohlc_map: HashMap<(String, KlineInterval), (i64, Decimal, Decimal, Decimal, Decimal)> = HashMap::new();
-    // 记录每个周期上次发送的 close
-    let mut last_sent: HashMap<(String, KlineInterval), Decimal> = HashMap::new();
-    // 每个 config.name 上次持久化的 5 秒分组
+    // 每个指数和篮子的 K 线周期
+    let mut intervals: HashMap<String, Vec<KlineInterval>> = index_configs
+        .iter()
+        .map(|c| (c.name.clone(), c.kline_intervals()))
+        .chain(basket_configs.iter().map(|b| (b.name.clone(), b.kline_intervals())))
+        .collect();
+    // 每个指数派生的平滑序列，与原始指数一样生成 K 线并持久化
+    let mut smoothers: HashMap<String, Vec<SmoothedSeries>> = index_configs
+        .iter()
+        .map(|c| {
+            let series = c.derived_series().into_iter().map(|d| SmoothedSeries::new(&c.name, d)).collect();
+            (c.name.clone(), series)
+        })
+        .collect();
+    // 行情 key -> 直接或通过 index(NAME) 间接引用它的指数，标记价格的永续行情算作其现货指数的输入
+    let mark_inputs = calculators.mark_inputs().await;
+    let mut input_map = build_input_map(&index_configs, &calculators.order().await, &mark_inputs);
+    // 轮询间隔取所有指数中最短的发布间隔，每个指数按自己的 min_publish_interval_ms / calc_interval_ms 发布
+    let mut tick_ms = tick_interval_ms(&index_configs);
+    let mut ticker = new_ticker(tick_ms);
+    // 输入行情已变化、等待重新计算的指数
+    let mut dirty: HashSet<String> = HashSet::new();
+    // 每个指数上次发布的时间（毫秒）
+    let mut last_calcs: HashMap<String, i64> = HashMap::new();
+    let mut last_db_poll = 0i64;
+
+    // 每个指数的周期 OHLC
+    let mut ohlc_map: HashMap<(String, KlineInterval), Ohlc> = HashMap::new();
+    // 每个 config.name 上次持久化的分组（persist_interval_secs）
     let mut last_groups: HashMap<String, i64> = HashMap::new();
+    // 每个指数最近一次满足 min_sources 的结果，降级时保持该值
+    let mut held: HashMap<String, Index> = HashMap::new();
+    // 当前处于降级状态的指数
+    let mut degraded_set: HashSet<String> = HashSet::new();
+    // 等待 activate_at 的新指数，到点后在运行中启用
+    let mut scheduled: Vec<IndexConfig> = Vec::new();
+    // 新公式版本写入后立即通知，不必等到下一次轮询；监听失败时只依赖轮询
+    let mut version_listener = match config_repo.listen_config_versions().await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            warn!("Error listening for formula versions, falling back to polling: {:?}", e);
+            None
+        }
+    };
 
     loop {
-        sleep(Duration::from_secs(1)).await;
-        let loop_start = Instant::now(); // 记录循环开始时间
+        // 下一个公式版本的生效时间或新指数的启用时间，到点立即唤醒
+        let next_activation = pending_versions
+            .iter()
+            .map(|v| v.effective_from)
+            .chain(scheduled.iter().filter_map(|c| c.activate_at))
+            .min();
+        let activation_delay = next_activation
+            .and_then(|at| (at - chrono::Utc::now()).to_std().ok())
+            .unwrap_or(Duration::ZERO);
+
+        tokio::select! {
+            Some(key) = changed_rx.recv() => {
+                mark_dirty(&input_map, &mut dirty, &key);
+                while let Ok(key) = changed_rx.try_recv() {
+                    mark_dirty(&input_map, &mut dirty, &key);
+                }
+            }
+            _ = ticker.tick() => {}
+            _ = tokio::time::sleep(activation_delay), if next_activation.is_some() => {}
+            Some(Ok(_)) = async { Some(version_listener.as_mut()?.recv().await) }, if version_listener.is_some() => {
+                last_db_poll = 0;
+            }
+        }
 
         let now = chrono::Utc::now();
         let now_timestamp = now.timestamp();
         let index_id = now.timestamp_millis();
 
-        for config in &index_configs {
-            let calcs = calculators.calculators.read().await;
-            if let Some(calc) = calcs.get(&config.name) {
-                if let Some(idx) = calc.calculate_index(
-                    &config.name,
-                    &config.formula,
-                    now_timestamp as u64,
-                ) {
-                    debug!(
-                        "Index {} = {} , formula: {}, computed_formula: {}",
-                        idx.symbol, idx.last, idx.formula, idx.computed_formula
-                    );
-
-                    // ---------------- 多周期 K 线 ----------------
-                    for &interval in &intervals {
-                        let interval_sec = interval.seconds();
-                        let interval_key = (idx.symbol.clone(), interval);
-                        let aligned_ts = (now_timestamp / interval_sec) * interval_sec;
-
-                        match ohlc_map.entry(interval_key.clone()) {
-                            Entry::Occupied(mut occ) => {
-                                let entry = occ.get_mut();
-                                if entry.0 == aligned_ts {
-                                    // 同周期，更新 OHLC
-                                    entry.2 = entry.2.max(idx.last); // high
-                                    entry.3 = entry.3.min(idx.last); // low
-                                    entry.4 = idx.last;              // close
-                                } else {
-                                    // 跨周期
-                                    let prev_kline = IndexKlineData::new(
-                                        Some(entry.0),
-                                        idx.symbol.clone(),
-                                        interval,
-                                        entry.1, entry.2, entry.3, entry.4,
-                                        entry.0,
-                                    );
-                                    let _ = kline_sender.send(prev_kline);
-
-                                    // 初始化新周期 OHLC
-                                    let last_close = entry.4; // 上一个周期的 close
-                                    *entry = (aligned_ts, last_close, last_close, last_close, last_close);
-
-                                    // 立即发送新周期初始 K 线
-                                    let init_kline = IndexKlineData::new(
-                                        Some(aligned_ts),
-                                        idx.symbol.clone(),
-                                        interval,
-                                        last_close, last_close, last_close, last_close,
-                                        aligned_ts,
-                                    );
-                                    let _ = kline_sender.send(init_kline);
-                                }
-                            }
-                            Entry::Vacant(vac) => {
-                                // 第一次出现该指数周期，初始化 OHLC
-                                vac.insert((aligned_ts, idx.last, idx.last, idx.last, idx.last));
-
-                                let init_kline = IndexKlineData::new(
-                                    Some(aligned_ts),
-                                    idx.symbol.clone(),
-                                    interval,
-                                    idx.last, idx.last, idx.last, idx.last,
-                                    aligned_ts,
-                                );
-                                let _ = kline_sender.send(init_kline);
-                            }
+        // ---------------- 手动恢复熔断、新指数、新公式版本、篮子调仓（每5秒检查一次） ----------------
+        if index_id - last_db_poll >= DB_POLL_MS {
+            last_db_poll = index_id;
+            match config_repo.take_breaker_resets().await {
+                Ok(names) => {
+                    for name in names {
+                        match calculators.reset_breaker(&name).await {
+                            Some(event) => publish_breaker_event(&config_repo, &name, event),
+                            None => info!("Index {} breaker reset requested but not tripped", name),
                         }
+                    }
+                }
+                Err(e) => error!("Error loading breaker resets: {:?}", e),
+            }
+            match config_repo.get_scheduled_configs().await {
+                Ok(configs) => {
+                    for config in configs {
+                        if scheduled.iter().any(|c| c.name == config.name) {
+                            continue;
+                        }
+                        info!("Index {} scheduled to activate at {:?}: {}", config.name, config.activate_at, config.formula);
+                        // 新指数引用的行情提前订阅，启用时已有价格
+                        subscribe_formula(&manager, &config_repo, &config.name, &config.formula).await;
+                        scheduled.push(config);
+                    }
+                }
+                Err(e) => error!("Error loading scheduled indices: {:?}", e),
+            }
+            match config_repo.get_config_versions_after(last_version_id).await {
+                Ok(versions) => {
+                    let mut installed = false;
+                    for v in versions {
+                        last_version_id = last_version_id.max(v.id);
+                        let known = index_configs.iter().chain(&scheduled).any(|c| c.name == v.index_name);
+                        if known {
+                            info!("Index {} formula version {} scheduled at {}: {}", v.index_name, v.id, v.effective_from, v.formula);
+                            // 新版本引用的行情提前订阅，生效时已有价格
+                            subscribe_formula(&manager, &config_repo, &v.index_name, &v.formula).await;
+                            pending_versions.push(v);
+                            installed = true;
+                        } else {
+                            warn!("Index {} formula version {} ignored: index is not running", v.index_name, v.id);
+                        }
+                    }
+                    if installed {
+                        apply_formulas(&calculators, &index_configs, &pending_versions).await;
+                    }
+                }
+                Err(e) => error!("Error loading formula versions: {:?}", e),
+            }
+            match config_repo.get_pending_basket_rebalances().await {
+                Ok(requests) => {
+                    let prices: HashMap<String, Decimal> =
+                        held.iter().map(|(name, idx)| (name.clone(), idx.last)).collect();
+                    // 同一篮子的多个请求合并为一次调仓
+                    let mut request_ids: HashMap<String, Vec<i64>> = HashMap::new();
+                    for (id, name) in requests {
+                        request_ids.entry(name).or_default().push(id);
+                    }
+                    for (name, ids) in request_ids {
+                        rebalance_basket(&calculators, &config_repo, &name, &ids, &prices).await;
+                    }
+                }
+                Err(e) => error!("Error loading basket rebalances: {:?}", e),
+            }
+        }
+
+        // ---------------- 新指数到达启用时间后开始计算 ----------------
+        if scheduled.iter().any(|c| c.activate_at.is_some_and(|at| at <= now)) {
+            // 到点的指数无论成功与否都移出等待列表，失败时下次轮询重新加载
+            scheduled.retain(|c| c.activate_at.is_none_or(|at| at > now));
+            for config in activate_scheduled(&calculators, &config_repo, &manager, &index_configs).await {
+                info!("Index {} activated by schedule: {}", config.name, config.formula);
+                intervals.insert(config.name.clone(), config.kline_intervals());
+                let series = config.derived_series().into_iter().map(|d| SmoothedSeries::new(&config.name, d)).collect();
+                smoothers.insert(config.name.clone(), series);
+                dirty.insert(config.name.clone());
+                index_configs.push(config);
+            }
+            apply_formulas(&calculators, &index_configs, &pending_versions).await;
+            input_map = build_input_map(&index_configs, &calculators.order().await, &mark_inputs);
+            if tick_interval_ms(&index_configs) < tick_ms {
+                tick_ms = tick_interval_ms(&index_configs);
+                ticker = new_ticker(tick_ms);
+            }
+        }
 
-                        // 持续更新当前周期 K 线
-                        let current_kline = ohlc_map.get(&interval_key).unwrap();
-                        let kline = IndexKlineData::new(
-                            Some(current_kline.0),
-                            idx.symbol.clone(),
-                            interval,
-                            current_kline.1,
-                            current_kline.2,
-                            current_kline.3,
-                            current_kline.4,
-                            current_kline.0,
+        // ---------------- 公式版本到达生效时间后切换 ----------------
+        if pending_versions.iter().any(|v| v.effective_from <= now) {
+            let (mut activated, rest): (Vec<_>, Vec<_>) =
+                pending_versions.drain(..).partition(|v| v.effective_from <= now);
+            pending_versions = rest;
+            activated.sort_by_key(|v| (v.effective_from, v.id));
+            for v in activated {
+                if let Some(config) = index_configs.iter_mut().find(|c| c.name == v.index_name) {
+                    info!("Index {} switched to formula version {}: {}", v.index_name, v.id, v.formula);
+                    config.formula = v.formula;
+                    config.version_id = Some(v.id);
+                    dirty.insert(config.name.clone());
+                }
+            }
+
+            apply_formulas(&calculators, &index_configs, &pending_versions).await;
+            input_map = build_input_map(&index_configs, &calculators.order().await, &mark_inputs);
+        }
+
+        // 到期的指数：输入变化且距上次发布超过 min_publish_interval_ms，或距上次发布超过 calc_interval_ms
+        let due: HashSet<String> = index_configs
+            .iter()
+            .filter(|c| {
+                let elapsed = index_id - last_calcs.get(&c.name).copied().unwrap_or(0);
+                (dirty.contains(&c.name) && elapsed >= c.min_publish_interval_ms as i64)
+                    || elapsed >= c.calc_interval_ms.max(MIN_CALC_INTERVAL_MS) as i64
+            })
+            .map(|c| c.name.clone())
+            .collect();
+        if due.is_empty() {
+            continue;
+        }
+        let loop_start = Instant::now(); // 记录循环开始时间
+
+        // 按依赖顺序计算，index(NAME) 引用的指数先算
+        let results = calculators.calculate_all(&index_configs, now_timestamp as u64, &due, &held).await;
+        for (config, calculated, outcome) in results {
+            // 未到该指数的发布时间
+            if !due.contains(&config.name) {
+                continue;
+            }
+            dirty.remove(&config.name);
+            last_calcs.insert(config.name.clone(), index_id);
+
+            // ---------------- min_sources 检查 ----------------
+            let source_count = calculated.as_ref().map_or(0, |idx| idx.source_count);
+            let quorum_met = source_count >= config.min_sources.max(1) as usize;
+            // 本轮计算剔除的行情：降级或熔断时发布的是保持值，但剔除原因仍以本轮为准
+            let (excluded_sources, stale_sources) = calculated
+                .as_ref()
+                .map(|idx| (idx.excluded_sources.join(","), idx.stale_sources.join(",")))
+                .unwrap_or_default();
+            let (idx, degraded, breaker_tripped) = match calculated {
+                Some(idx) if quorum_met => {
+                    if degraded_set.remove(&config.name) {
+                        info!(
+                            "Index {} recovered: {} live sources, min_sources {}",
+                            config.name, source_count, config.min_sources
                         );
-                        let _ = kline_sender.send(kline);
                     }
 
+                    // ---------------- 熔断检查（已在 calculate_all 中完成） ----------------
+                    if let Some(event) = outcome.event {
+                        publish_breaker_event(&config_repo, &config.name, event);
+                    }
+                    if outcome.hold {
+                        match held.get(&config.name) {
+                            Some(last) => (last.clone(), false, true),
+                            None => continue,
+                        }
+                    } else {
+                        held.insert(config.name.clone(), idx.clone());
+                        (idx, false, false)
+                    }
+                }
+                _ => match held.get(&config.name) {
+                    Some(last) => {
+                        if degraded_set.insert(config.name.clone()) {
+                            warn!(
+                                "Index {} degraded: {} live sources, min_sources {}, holding last value {}",
+                                config.name, source_count, config.min_sources, last.last
+                            );
+                        }
+                        (last.clone(), true, false)
+                    }
+                    None => continue,
+                },
+            };
+
+            // ---------------- EDP（时间加权平均），降级或熔断保持的值不计入，与结算价采样一致 ----------------
+            let held_value = degraded || breaker_tripped;
+            let edp = calculators.calculate_edp(&config.name, index_id, (!held_value).then_some(idx.last)).await;
+            if !held_value {
+                calculators.record_sample(&config.name, index_id, &idx).await;
+            }
+
+            debug!(
+                "Index {} = {} , edp: {:?}, formula: {}, computed_formula: {}, excluded: {:?}, stale: {:?}, degraded: {}, breaker_tripped: {}",
+                idx.symbol, idx.last, edp, idx.formula, idx.computed_formula, idx.excluded_sources, idx.stale_sources, degraded, breaker_tripped
+            );
+
+            // ---------------- 多周期 K 线（降级或熔断时不更新） ----------------
+            let index_intervals = intervals.get(&config.name).map_or(&[][..], |v| v.as_slice());
+            if !held_value {
+                update_klines(&mut ohlc_map, &kline_sender, &idx.symbol, idx.last, index_intervals, now_timestamp);
+            }
+
+            // ---------------- 派生平滑序列（EMA / SMA），降级或熔断时保持 ----------------
+            let series = smoothers.get_mut(&config.name).map_or(&mut [][..], |v| v.as_mut_slice());
+            if !held_value {
+                for s in series.iter_mut() {
+                    let value = calculators.round(&config.name, s.update(index_id, idx.last)).await;
+                    s.last = Some(value);
+                    debug!("Index {} = {}, {} of {}", s.name, value, s.series, config.name);
+                    update_klines(&mut ohlc_map, &kline_sender, &s.name, value, index_intervals, now_timestamp);
+                }
+            }
+
+            // ---------------- 标记价格（溢价 EMA），降级或熔断时不更新 ----------------
+            let marks = if held_value {
+                Vec::new()
+            } else {
+                calculators.calculate_marks(&config.name, index_id, idx.last).await
+            };
+            for (name, mark) in &marks {
+                debug!(
+                    "Mark {} = {}, index: {}, perp: {:?}, premium: {:?}, premium_avg: {}, premium_ema: {}",
+                    name, mark.mark_price, mark.index_price, mark.perp_price, mark.premium_index, mark.premium_avg, mark.premium_ema
+                );
+            }
+
+            // ---------------- 每 persist_interval_secs 秒持久化 ----------------
+            let group = index_id / (config.persist_interval_secs.max(1) as i64 * 1000);
+            let config_name_clone = String::from(&config.name);
+            let last_group = last_groups.entry(config_name_clone.clone()).or_insert(0);
 
-                    // ---------------- 每5秒持久化 ----------------
-                    let group = now_timestamp / 5;
-                    let config_name_clone = String::from(&config.name);
-                    let last_group = last_groups.entry(config_name_clone.clone()).or_insert(0);
+            if group != *last_group {
+                *last_group = group;
 
-                    if group != *last_group {
-                        *last_group = group;
+                let config_repo = config_repo.clone();
+                let index_data = IndexData {
+                    excluded_sources,
+                    stale_sources,
+                    ..IndexData::new(
+                        Some(index_id),
+                        idx.symbol.clone(),
+                        idx.last,
+                        idx.formula.clone(),
+                        degraded,
+                        edp,
+                        breaker_tripped,
+                        config.version_id,
+                        idx.raw_last,
+                        idx.conversion_rate,
+                    )
+                };
+                debug!("Persisting index_data for {}: {:?}", &config_name_clone, index_data);
 
-                        let config_repo = config_repo.clone();
-                        let index_data = IndexData::new(
+                let mark_data: Vec<MarkPriceData> = marks
+                    .into_iter()
+                    .map(|(name, mark)| MarkPriceData {
+                        id: Some(index_id),
+                        name,
+                        index_name: config.name.clone(),
+                        index_price: mark.index_price,
+                        perp_price: mark.perp_price,
+                        premium_index: mark.premium_index,
+                        premium_avg: mark.premium_avg,
+                        premium_ema: mark.premium_ema,
+                        mark_price: mark.mark_price,
+                        created_at: now,
+                        updated_at: now,
+                    })
+                    .collect();
+                let derived_data: Vec<IndexData> = series
+                    .iter()
+                    .filter_map(|s| {
+                        Some(IndexData::new(
                             Some(index_id),
-                            idx.symbol.clone(),
-                            idx.last,
-                            idx.formula.clone(),
-                        );
-                        debug!("Persisting index_data for {}: {:?}", &config_name_clone, index_data);
-
-                        tokio::spawn(async move {
-                            if let Err(e) = config_repo
-                                .insert_index_data(&format!("index_data_{}", &config_name_clone), &index_data)
-                                .await
-                            {
-                                error!("Error saving index data: {:?}", e);
-                            }
-                        });
+                            s.name.clone(),
+                            s.last?,
+                            s.formula(&config.name),
+                            degraded,
+                            None,
+                            breaker_tripped,
+                            config.version_id,
+                            None,
+                            None,
+                        ))
+                    })
+                    .collect();
+
+                tokio::spawn(async move {
+                    if let Err(e) = config_repo
+                        .insert_index_data(&format!("index_data_{}", &config_name_clone), &index_data)
+                        .await
+                    {
+                        error!("Error saving index data: {:?}", e);
                     }
-                }
+                    for data in derived_data {
+                        let table_name = format!("index_data_{}", data.symbol.to_lowercase());
+                        if let Err(e) = config_repo.insert_index_data(&table_name, &data).await {
+                            error!("Error saving derived index data: {:?}", e);
+                        }
+                    }
+                    for data in mark_data {
+                        let table_name = format!("mark_price_data_{}", data.name.to_lowercase());
+                        if let Err(e) = config_repo.insert_mark_price(&table_name, &data).await {
+                            error!("Error saving mark price: {:?}", e);
+                        }
+                    }
+                });
+            }
+        }
+
+        // ---------------- 一篮子指数（成分取各指数最近一次有效发布值） ----------------
+        let prices: HashMap<String, Decimal> = held.iter().map(|(name, idx)| (name.clone(), idx.last)).collect();
+        for (basket, rebalance) in calculators.plan_initial_baskets(&prices, &due).await {
+            initialize_basket(&calculators, &config_repo, basket, rebalance).await;
+        }
+        for basket in calculators.calculate_baskets(&prices, &due).await {
+            let Some(config) = basket_configs.iter().find(|b| b.name == basket.name) else {
+                continue;
+            };
+            let level = basket
+                .level
+                .round_dp_with_strategy(config.scale.clamp(0, 18) as u32, RoundingStrategy::MidpointAwayFromZero);
+            // 任一成分降级时篮子也标记为降级
+            let degraded = basket.constituents.iter().any(|c| degraded_set.contains(c));
+            debug!("Basket {} = {}, formula: {}, degraded: {}", basket.name, level, basket.formula, degraded);
+
+            let basket_intervals = intervals.get(&basket.name).map_or(&[][..], |v| v.as_slice());
+            update_klines(&mut ohlc_map, &kline_sender, &basket.name, level, basket_intervals, now_timestamp);
+
+            let group = index_id / (config.persist_interval_secs.max(1) as i64 * 1000);
+            let last_group = last_groups.entry(basket.name.clone()).or_insert(0);
+            if group != *last_group {
+                *last_group = group;
+                let index_data = IndexData::new(
+                    Some(index_id),
+                    basket.name.clone(),
+                    level,
+                    basket.formula,
+                    degraded,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                );
+                let config_repo = config_repo.clone();
+                tokio::spawn(async move {
+                    let table_name = format!("index_data_{}", index_data.symbol.to_lowercase());
+                    if let Err(e) = config_repo.insert_index_data(&table_name, &index_data).await {
+                        error!("Error saving basket index data: {:?}", e);
+                    }
+                });
             }
         }
 
@@ -152,3 +466,338 @@ pub async fn run_index_calculator(
         }
     }
 }
+
+/// 所有指数中最短的发布间隔（毫秒）
+fn tick_interval_ms(index_configs: &[IndexConfig]) -> u64 {
+    index_configs
+        .iter()
+        .map(|c| c.min_publish_interval_ms.min(c.calc_interval_ms).max(MIN_TICK_MS))
+        .min()
+        .unwrap_or(1000) as u64
+}
+
+fn new_ticker(tick_ms: u64) -> tokio::time::Interval {
+    let mut ticker = tokio::time::interval(Duration::from_millis(tick_ms));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
+
+/// 启用 activate_at 已到的新指数：建表、订阅行情并创建计算器，返回启用成功的配置；
+/// 调用方随后需要重新计算顺序和路由
+async fn activate_scheduled(
+    calculators: &Arc<CalculatorManager>,
+    config_repo: &Arc<ConfigRepository>,
+    manager: &ExchangeManager,
+    running: &[IndexConfig],
+) -> Vec<IndexConfig> {
+    let names = match config_repo.activate_scheduled_configs().await {
+        Ok(names) => names,
+        Err(e) => {
+            error!("Error activating scheduled indices: {:?}", e);
+            return Vec::new();
+        }
+    };
+    if names.is_empty() {
+        return Vec::new();
+    }
+    // 重新加载以取得当前生效的公式版本
+    let (configs, settlement_configs) =
+        match tokio::try_join!(config_repo.get_active_configs(), config_repo.get_active_settlement_configs()) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                error!("Error loading activated indices {:?}: {:?}", names, e);
+                return Vec::new();
+            }
+        };
+    let mut activated = Vec::new();
+    for config in configs {
+        if !names.contains(&config.name) || running.iter().any(|c| c.name == config.name) {
+            continue;
+        }
+        let tables = std::iter::once(config.name.clone())
+            .chain(config.derived_series().into_iter().map(|d| d.index_name(&config.name)));
+        let mut created = true;
+        for table in tables {
+            if let Err(e) = config_repo.create_index_data_table_if_not_exists(&table).await {
+                error!("Error creating index_data table for {}: {:?}", table, e);
+                created = false;
+            }
+        }
+        if !created {
+            continue;
+        }
+        subscribe_formula(manager, config_repo, &config.name, &config.formula).await;
+        calculators.add_calculator(IndexCalculator::from_config(&config, &settlement_configs)).await;
+        activated.push(config);
+    }
+    activated
+}
+
+/// 按当前公式和尚未生效的版本重新计算顺序和行情路由
+async fn apply_formulas(
+    calculators: &Arc<CalculatorManager>,
+    index_configs: &[IndexConfig],
+    pending_versions: &[IndexConfigVersion],
+) {
+    let formulas: Vec<(&str, &str)> = index_configs
+        .iter()
+        .map(|c| (c.name.as_str(), c.formula.as_str()))
+        .collect();
+    let pending_formulas: Vec<(&str, &str)> = pending_versions
+        .iter()
+        .map(|v| (v.index_name.as_str(), v.formula.as_str()))
+        .collect();
+    if let Err(e) = calculators
+        .apply_formulas(&formulas, &pending_formulas, &conversion_deps(index_configs))
+        .await
+    {
+        error!("Error applying formula versions: {:?}", e);
+    }
+}
+
+/// 追加订阅公式引用的行情，已订阅的行情由客户端跳过
+async fn subscribe_formula(manager: &ExchangeManager, config_repo: &ConfigRepository, name: &str, formula: &str) {
+    let enabled_exchanges: HashSet<String> = match config_repo.get_enabled_tasks().await {
+        Ok(tasks) => tasks.into_iter().filter(|t| t.is_enabled).map(|t| t.exchange_name).collect(),
+        Err(e) => {
+            error!("Error loading tasks: {:?}", e);
+            return;
+        }
+    };
+    let symbols_map =
+        match ExchangeInitializer::resolve_symbols(config_repo, &[(name, formula)], &enabled_exchanges, &mut HashSet::new()).await {
+            Ok(symbols_map) => symbols_map,
+            Err(e) => {
+                error!("Error resolving symbols for {}: {:?}", name, e);
+                return;
+            }
+        };
+    for (exchange, symbols) in symbols_map {
+        let book_symbols: HashSet<String> =
+            symbols.iter().filter(|s| s.book).map(|s| s.third_symbol_name.clone()).collect();
+        let symbols: HashMap<String, String> = symbols
+            .into_iter()
+            .map(|s| (s.third_symbol_name, s.symbol_name))
+            .collect();
+        if !manager.subscribe(&exchange, symbols, book_symbols).await {
+            warn!("Index {} references {} which is not connected, restart to subscribe", name, exchange.name());
+        }
+    }
+}
+
+/// 根据公式建立 行情 key -> 指数 的映射，index(NAME) 引用的指数的输入也算作本指数的输入；
+/// extra 为 (指数名称, 公式)，其行情只算作该指数的输入
+fn build_input_map(
+    index_configs: &[IndexConfig],
+    order: &[String],
+    extra: &[(String, String)],
+) -> HashMap<String, HashSet<String>> {
+    let mut inputs: HashMap<&str, HashSet<String>> = HashMap::new();
+    for name in order {
+        let Some(config) = index_configs.iter().find(|c| &c.name == name) else {
+            continue;
+        };
+        let mut keys = HashSet::new();
+        if let Ok(expr) = formula::parse(&config.formula) {
+            keys.extend(expr.sources().into_iter().map(|s| s.key()));
+            for r in expr.index_refs() {
+                if let Some(ref_keys) = inputs.get(r) {
+                    keys.extend(ref_keys.iter().cloned());
+                }
+            }
+        }
+        inputs.insert(name, keys);
+    }
+
+    let mut input_map: HashMap<String, HashSet<String>> = HashMap::new();
+    for (name, keys) in inputs {
+        for key in keys {
+            input_map.entry(key).or_default().insert(name.to_string());
+        }
+    }
+    for (name, formula) in extra {
+        if let Ok(expr) = formula::parse(formula) {
+            for source in expr.sources() {
+                input_map.entry(source.key()).or_default().insert(name.clone());
+            }
+        }
+    }
+    input_map
+}
+
+fn mark_dirty(input_map: &HashMap<String, HashSet<String>>, dirty: &mut HashSet<String>, key: &str) {
+    if let Some(names) = input_map.get(key) {
+        dirty.extend(names.iter().cloned());
+    }
+}
+
+/// 按 basket_constituent 中的最新成分调仓，除数、持仓和调仓记录保存成功后才生效，并标记请求已处理；
+/// 失败时请求保持未处理，下次轮询时重试
+async fn rebalance_basket(
+    calculators: &Arc<CalculatorManager>,
+    config_repo: &Arc<ConfigRepository>,
+    basket_name: &str,
+    request_ids: &[i64],
+    prices: &HashMap<String, Decimal>,
+) {
+    let constituents = match config_repo.get_basket_constituents(basket_name).await {
+        Ok(constituents) => constituents,
+        Err(e) => {
+            error!("Error loading basket {} constituents: {:?}", basket_name, e);
+            return;
+        }
+    };
+    let targets: Vec<BasketTarget> = constituents
+        .into_iter()
+        .map(|c| BasketTarget {
+            index_name: c.index_name,
+            weight: c.weight,
+            quantity: c.quantity,
+        })
+        .collect();
+    let (basket, rebalance) = match calculators.plan_basket_rebalance(basket_name, &targets, prices).await {
+        Ok(planned) => planned,
+        Err(e) => {
+            error!("Basket {} rebalance failed: {:?}", basket_name, e);
+            return;
+        }
+    };
+    if let Err(e) = config_repo.save_basket_rebalance(&rebalance, request_ids).await {
+        error!("Error saving basket {} rebalance, keeping current holdings: {:?}", basket_name, e);
+        return;
+    }
+    log_basket_rebalance(&rebalance);
+    calculators.replace_basket(basket).await;
+}
+
+/// 首次建仓：除数和持仓写入 basket_rebalance 成功后才生效，失败时篮子保持未建仓，下次计算时重试
+async fn initialize_basket(
+    calculators: &Arc<CalculatorManager>,
+    config_repo: &Arc<ConfigRepository>,
+    basket: BasketCalculator,
+    rebalance: BasketRebalance,
+) {
+    if let Err(e) = config_repo.save_basket_rebalance(&rebalance, &[]).await {
+        error!("Error saving basket {} initial rebalance: {:?}", rebalance.basket_name, e);
+        return;
+    }
+    log_basket_rebalance(&rebalance);
+    calculators.replace_basket(basket).await;
+}
+
+fn log_basket_rebalance(rebalance: &BasketRebalance) {
+    info!(
+        "Basket {} rebalanced at level {}: divisor {:?} -> {}, holdings {:?}",
+        rebalance.basket_name, rebalance.level, rebalance.old_divisor, rebalance.new_divisor, rebalance.holdings
+    );
+}
+
+/// 记录熔断事件日志并异步写入 circuit_breaker_event
+fn publish_breaker_event(config_repo: &Arc<ConfigRepository>, index_name: &str, event: BreakerEvent) {
+    match event.kind {
+        BreakerEventKind::Tripped => error!(
+            "Index {} circuit breaker tripped ({}): reference {:?}, candidate {:?}, move {:?}",
+            index_name, event.reason.as_str(), event.reference, event.candidate, event.move_ratio
+        ),
+        _ => info!(
+            "Index {} circuit breaker {} ({}): reference {:?}, candidate {:?}",
+            index_name, event.kind.as_str(), event.reason.as_str(), event.reference, event.candidate
+        ),
+    }
+
+    let record = CircuitBreakerEvent {
+        id: None,
+        index_name: index_name.to_string(),
+        event: event.kind.as_str().to_string(),
+        reason: event.reason.as_str().to_string(),
+        reference_price: event.reference,
+        candidate_price: event.candidate,
+        move_ratio: event.move_ratio,
+        created_at: chrono::Utc::now(),
+    };
+    let config_repo = config_repo.clone();
+    tokio::spawn(async move {
+        if let Err(e) = config_repo.insert_breaker_event(&record).await {
+            error!("Error saving breaker event: {:?}", e);
+        }
+    });
+}
+
+/// 用最新指数值更新各周期 K 线，并发送给 kline_saver
+fn update_klines(
+    ohlc_map: &mut HashMap<(String, KlineInterval), Ohlc>,
+    kline_sender: &UnboundedSender<IndexKlineData>,
+    symbol: &str,
+    last: Decimal,
+    intervals: &[KlineInterval],
+    now_timestamp: i64,
+) {
+    for &interval in intervals {
+        let interval_sec = interval.seconds();
+        let interval_key = (symbol.to_string(), interval);
+        let aligned_ts = (now_timestamp / interval_sec) * interval_sec;
+
+        match ohlc_map.entry(interval_key.clone()) {
+            Entry::Occupied(mut occ) => {
+                let entry = occ.get_mut();
+                if entry.0 == aligned_ts {
+                    // 同周期，更新 OHLC
+                    entry.2 = entry.2.max(last); // high
+                    entry.3 = entry.3.min(last); // low
+                    entry.4 = last;              // close
+                } else {
+                    // 跨周期
+                    let prev_kline = IndexKlineData::new(
+                        Some(entry.0),
+                        symbol.to_string(),
+                        interval,
+                        entry.1, entry.2, entry.3, entry.4,
+                        entry.0,
+                    );
+                    let _ = kline_sender.send(prev_kline);
+
+                    // 初始化新周期 OHLC
+                    let last_close = entry.4; // 上一个周期的 close
+                    *entry = (aligned_ts, last_close, last_close, last_close, last_close);
+
+                    // 立即发送新周期初始 K 线
+                    let init_kline = IndexKlineData::new(
+                        Some(aligned_ts),
+                        symbol.to_string(),
+                        interval,
+                        last_close, last_close, last_close, last_close,
+                        aligned_ts,
+                    );
+                    let _ = kline_sender.send(init_kline);
+                }
+            }
+            Entry::Vacant(vac) => {
+                // 第一次出现该指数周期，初始化 OHLC
+                vac.insert((aligned_ts, last, last, last, last));
+
+                let init_kline = IndexKlineData::new(
+                    Some(aligned_ts),
+                    symbol.to_string(),
+                    interval,
+                    last, last, last, last,
+                    aligned_ts,
+                );
+                let _ = kline_sender.send(init_kline);
+            }
+        }
+
+        // 持续更新当前周期 K 线
+        let current_kline = ohlc_map.get(&interval_key).unwrap();
+        let kline = IndexKlineData::new(
+            Some(current_kline.0),
+            symbol.to_string(),
+            interval,
+            current_kline.1,
+            current_kline.2,
+            current_kline.3,
+            current_kline.4,
+            current_kline.0,
+        );
+        let _ = kline_sender.send(kline);
+    }
+}
@@ -1,4 +1,6 @@
 pub mod price_updater;
 pub mod index_calculator_task;
 pub mod market_printer;
+pub mod settlement_scheduler;
+pub mod funding_scheduler;
 pub(crate) mod kline_saver;
\ No newline at end of file
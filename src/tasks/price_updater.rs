@@ -1,27 +1,35 @@
 use crate::core::index::calculator_manager::CalculatorManager;
-use crate::core::model::Symbol;
-use crate::exchanges::ExchangeEnum;
-use std::collections::HashMap;
+use crate::core::index::index_calculator::PriceEntry;
+use crate::core::model::PriceUpdate;
 use std::sync::Arc;
 use rust_decimal::Decimal;
-use crate::core::exchange::exchange_manager::ExchangeManager;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::warn;
 
+/// 接收 WebSocket 客户端推送的行情，写入引用它的计算器后通知指数计算任务哪个行情发生了变化
 pub async fn run_price_updater(
-    manager: Arc<ExchangeManager>,
+    mut price_rx: UnboundedReceiver<PriceUpdate>,
     calculators: Arc<CalculatorManager>,
-    task_symbols_map: Arc<HashMap<ExchangeEnum, Vec<Symbol>>>,
+    changed_tx: UnboundedSender<String>,
 ) {
-    loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        for (exch, symbols) in task_symbols_map.iter() {
-            if let Some(client) = manager.get_client(exch) {
-                for symbol in symbols {
-                    if let Some(t) = client.get_ticker(&symbol.symbol_name) {
-                        let price = t.last_pr.parse::<Decimal>().unwrap_or(Decimal::ZERO);
-                        calculators.update_price(&symbol.symbol_name, &format!("{}.{}", exch.name(), &symbol.symbol_name), price).await;
-                    }
-                }
-            }
+    while let Some(update) = price_rx.recv().await {
+        let t = &update.ticker;
+        let price = PriceEntry {
+            price: t.last_pr.parse::<Decimal>().ok(),
+            ts: t.ts.parse::<i64>().unwrap_or(0),
+            bid: t.bid_pr.parse::<Decimal>().ok(),
+            ask: t.ask_pr.parse::<Decimal>().ok(),
+            book_ts: t.book_ts.parse::<i64>().unwrap_or(0),
+            received_at: t.received_at,
+            volume: t.quote_volume(),
+        };
+        let key = update.key();
+        if !calculators.route_price(&key, price).await {
+            continue;
+        }
+        if changed_tx.send(key).is_err() {
+            warn!("index calculator stopped, price updater exits");
+            return;
         }
     }
 }
@@ -0,0 +1,78 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use tokio::time::{sleep, Duration};
+use crate::core::db::config_repository::ConfigRepository;
+use crate::core::index::calculator_manager::CalculatorManager;
+use crate::core::model::{SettlementConfig, SettlementPrice};
+use tracing::{error, info, warn};
+
+/// 定时结算：到达结算时间后，取结算时间前 window_minutes 分钟内指数的时间加权平均写入 settlement_price
+pub async fn run_settlement_scheduler(
+    calculators: Arc<CalculatorManager>,
+    settlement_configs: Vec<SettlementConfig>,
+    config_repo: Arc<ConfigRepository>,
+) {
+    if settlement_configs.is_empty() {
+        return;
+    }
+
+    // 每个结算配置上次结算的时间，启动时不补算已经过去的结算
+    let now = Utc::now();
+    let mut last_settled: HashMap<i32, DateTime<Utc>> = settlement_configs
+        .iter()
+        .map(|c| (c.id, c.last_settle_at(now)))
+        .collect();
+
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        let now = Utc::now();
+
+        for config in &settlement_configs {
+            let settle_at = config.last_settle_at(now);
+            if last_settled.get(&config.id) == Some(&settle_at) {
+                continue;
+            }
+            last_settled.insert(config.id, settle_at);
+
+            let to_ms = settle_at.timestamp_millis();
+            let from_ms = to_ms - config.window_minutes as i64 * 60_000;
+            let Some((twap, samples)) = calculators.twap_between(&config.index_name, from_ms, to_ms).await else {
+                warn!(
+                    "Settlement {} {} at {} skipped: no index samples in last {} minutes",
+                    config.index_name, config.frequency, settle_at, config.window_minutes
+                );
+                continue;
+            };
+
+            let price = calculators.round(&config.index_name, twap).await;
+            let sources: BTreeSet<&str> = samples
+                .iter()
+                .flat_map(|s| s.sources.iter().map(String::as_str))
+                .collect();
+
+            let settlement = SettlementPrice {
+                id: None,
+                index_name: config.index_name.clone(),
+                frequency: config.frequency,
+                settle_at,
+                price,
+                window_minutes: config.window_minutes,
+                samples_count: samples.len() as i32,
+                sources: sources.into_iter().collect::<Vec<_>>().join(","),
+                created_at: now,
+            };
+            info!(
+                "Settlement {} {} at {} = {}, samples: {}, sources: {}",
+                settlement.index_name, settlement.frequency, settle_at, price, settlement.samples_count, settlement.sources
+            );
+
+            let config_repo = config_repo.clone();
+            tokio::spawn(async move {
+                if let Err(e) = config_repo.insert_settlement_price(&settlement).await {
+                    error!("Error saving settlement price: {:?}", e);
+                }
+            });
+        }
+    }
+}